@@ -0,0 +1,83 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 J.C. Moyer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Counts the heap allocations made by a native function that reads its string
+// argument with `check_str` versus one that converts it into an owned
+// `String`. The state is created with `State::new_native`, so Lua allocates
+// with the C allocator and only Rust allocations are counted.
+
+#[macro_use]
+extern crate lua;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use lua::libc::c_int;
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    System.dealloc(ptr, layout)
+  }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const ITERATIONS: usize = 100_000;
+
+fn borrowed(state: &mut lua::State) -> c_int {
+  let matches = state.check_str(1) == "needle";
+  state.push_bool(matches);
+  1
+}
+
+fn owned(state: &mut lua::State) -> c_int {
+  let arg = state.check_str(1).to_owned();
+  state.push_bool(arg == "needle");
+  1
+}
+
+fn run(label: &str, f: lua::Function) {
+  let mut state = lua::State::new_native();
+  let before = ALLOCATIONS.load(Ordering::Relaxed);
+  for _ in 0..ITERATIONS {
+    state.push_fn(f);
+    state.push_string("haystack");
+    state.call(1, 1);
+    state.pop(1);
+  }
+  let count = ALLOCATIONS.load(Ordering::Relaxed) - before;
+  println!("{:>9}: {} allocations for {} calls", label, count, ITERATIONS);
+}
+
+fn main() {
+  run("check_str", lua_func!(borrowed));
+  run("to_owned", lua_func!(owned));
+}
@@ -0,0 +1,84 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 J.C. Moyer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Counts the heap allocations made while setting the fields of a struct-like
+// table with `set_field`, which builds a `CString` for every key, versus
+// `set_field_static` with keys from `lua_cstr!`. The state is created with
+// `State::new_native`, so Lua allocates with the C allocator and only Rust
+// allocations are counted.
+
+#[macro_use]
+extern crate lua;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+  unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    System.alloc(layout)
+  }
+
+  unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    System.dealloc(ptr, layout)
+  }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const ITERATIONS: usize = 100_000;
+
+fn run<F: FnMut(&mut lua::State)>(label: &str, mut set_fields: F) {
+  let mut state = lua::State::new_native();
+  let before = ALLOCATIONS.load(Ordering::Relaxed);
+  for _ in 0..ITERATIONS {
+    state.create_table(0, 3);
+    set_fields(&mut state);
+    state.pop(1);
+  }
+  let count = ALLOCATIONS.load(Ordering::Relaxed) - before;
+  println!("{:>16}: {} allocations for {} tables", label, count, ITERATIONS);
+}
+
+fn main() {
+  run("set_field", |state| {
+    state.push_integer(1);
+    state.set_field(-2, "id");
+    state.push_string("point");
+    state.set_field(-2, "name");
+    state.push_number(0.5);
+    state.set_field(-2, "position");
+  });
+  run("set_field_static", |state| {
+    state.push_integer(1);
+    state.set_field_static(-2, lua_cstr!("id"));
+    state.push_string("point");
+    state.set_field_static(-2, lua_cstr!("name"));
+    state.push_number(0.5);
+    state.set_field_static(-2, lua_cstr!("position"));
+  });
+}
@@ -0,0 +1,47 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 J.C. Moyer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Compares `push_interned` against `push_string` when pushing the same field
+// names over and over, as a serializer would. Run with `--release`.
+
+extern crate lua;
+
+use std::time::Instant;
+
+const ITERATIONS: usize = 1_000_000;
+const FIELDS: [&'static str; 4] = ["id", "name", "position", "a field name that is long enough to bypass interning in Lua"];
+
+fn run<F: FnMut(&mut lua::State, usize)>(label: &str, state: &mut lua::State, mut push: F) {
+  let start = Instant::now();
+  for i in 0..ITERATIONS {
+    push(state, i % FIELDS.len());
+    state.pop(1);
+  }
+  println!("{:>13}: {:?}", label, start.elapsed());
+}
+
+fn main() {
+  let mut state = lua::State::new();
+  let keys: Vec<lua::Reference> = FIELDS.iter().map(|field| state.intern(field)).collect();
+  run("push_string", &mut state, |state, i| state.push_string(FIELDS[i]));
+  run("push_interned", &mut state, |state, i| state.push_interned(keys[i]));
+}
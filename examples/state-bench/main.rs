@@ -0,0 +1,47 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 J.C. Moyer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+// Compares creating states and running a small script with the Rust
+// allocator (`State::new`) and with the C allocator (`State::new_native`).
+// Run with `--release`.
+
+extern crate lua;
+
+use std::time::Instant;
+
+const ITERATIONS: usize = 10_000;
+const SCRIPT: &'static str = "local t = {} for i = 1, 100 do t[i] = tostring(i) end";
+
+fn run<F: Fn() -> lua::State>(label: &str, create: F) {
+  let start = Instant::now();
+  for _ in 0..ITERATIONS {
+    let mut state = create();
+    state.open_base();
+    assert_eq!(state.do_string(SCRIPT), lua::ThreadStatus::Ok);
+  }
+  println!("{:>10}: {:?}", label, start.elapsed());
+}
+
+fn main() {
+  run("new", lua::State::new);
+  run("new_native", lua::State::new_native);
+}
@@ -32,18 +32,30 @@ extern crate bitflags;
 
 pub use wrapper::state::{
   State,
+  StackGuard,
+  Thread,
+  MetatableBuilder,
   Extra,
 
   Arithmetic,
   Comparison,
   ThreadStatus,
+  StackError,
+  YieldError,
+  IntError,
+  NumberKind,
+  PackValue,
+  LuaValue,
   GcOption,
+  WeakMode,
   Type,
   Library,
 
   Reference,
   REFNIL, NOREF,
+  RegistryKey,
 
+  DebugInfo,
   HookMask,
   MASKCALL, MASKRET, MASKLINE, MASKCOUNT,
 
@@ -51,9 +63,17 @@ pub use wrapper::state::{
   RIDX_MAINTHREAD, RIDX_GLOBALS
 };
 
+pub use wrapper::coroutine::{
+  Coroutine,
+  CoResult
+};
+
 pub use wrapper::convert::{
   ToLua,
-  FromLua
+  ToLuaMulti,
+  FromLua,
+  FromLuaMulti,
+  Array
 };
 
 pub use ffi::lua_Number as Number;
@@ -12,6 +12,45 @@ macro_rules! lua_func {
   ($func:path) => { $crate::macros::_wrap(|s| $crate::macros::_check_type($func)(s)) }
 }
 
+/// Formats a string and pushes it onto the stack of a `State`, like
+/// `lua_pushfstring` but with Rust formatting syntax.
+///
+/// ```ignore
+/// lua_format!(state, "{}-{}", a, b);
+/// ```
+#[macro_export]
+macro_rules! lua_format {
+  ($state:expr, $($arg:tt)*) => { $state.push_fmt(format_args!($($arg)*)) }
+}
+
+/// Raises a Lua error with a formatted message, like `luaL_error` but with
+/// Rust formatting syntax. This never returns.
+///
+/// ```ignore
+/// lua_error!(state, "expected {} arguments, got {}", 2, n);
+/// ```
+#[macro_export]
+macro_rules! lua_error {
+  ($state:expr, $($arg:tt)*) => {{
+    $state.push_fmt(format_args!($($arg)*));
+    $state.error()
+  }}
+}
+
+/// Creates a `&'static CStr` from a string literal by appending a NUL byte at
+/// compile time, for use with `set_field_static`. Panics if the literal
+/// contains a NUL byte.
+///
+/// ```ignore
+/// state.set_field_static(-2, lua_cstr!("name"));
+/// ```
+#[macro_export]
+macro_rules! lua_cstr {
+  ($s:expr) => {
+    ::std::ffi::CStr::from_bytes_with_nul(concat!($s, "\0").as_bytes()).unwrap()
+  }
+}
+
 #[doc(hidden)]
 #[inline(always)]
 pub fn _check_type(f: fn(&mut State) -> c_int) -> fn(&mut State) -> c_int {
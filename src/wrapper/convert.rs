@@ -22,7 +22,10 @@
 
 //! Implements conversions for Rust types to and from Lua.
 
-use ::{State, Integer, Number, Function, Index};
+use libc::c_int;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use ::{State, Integer, Number, Function, Index, PackValue, LuaValue, Type};
 
 /// Trait for types that can be pushed onto the stack of a Lua state.
 ///
@@ -45,12 +48,25 @@ impl<'a> ToLua for &'a [u8] {
   }
 }
 
+impl ToLua for Vec<u8> {
+  fn to_lua(&self, state: &mut State) {
+    state.push_bytes(&self);
+  }
+}
+
 impl ToLua for String {
   fn to_lua(&self, state: &mut State) {
     state.push_string(&self);
   }
 }
 
+impl ToLua for char {
+  fn to_lua(&self, state: &mut State) {
+    let mut buf = [0; 4];
+    state.push_string(self.encode_utf8(&mut buf));
+  }
+}
+
 impl ToLua for Integer {
   fn to_lua(&self, state: &mut State) {
     state.push_integer(*self)
@@ -75,6 +91,36 @@ impl ToLua for Function {
   }
 }
 
+impl ToLua for PackValue {
+  fn to_lua(&self, state: &mut State) {
+    match *self {
+      PackValue::Integer(i) => state.push_integer(i),
+      PackValue::Number(n) => state.push_number(n),
+      PackValue::Bytes(ref bytes) => state.push_bytes(bytes),
+    }
+  }
+}
+
+/// Pushes the duration as a float number of seconds, which is what Lua's
+/// `os.time` and `os.clock` style APIs work with. Precision is limited to
+/// that of `Number`.
+impl ToLua for Duration {
+  fn to_lua(&self, state: &mut State) {
+    state.push_number(duration_to_secs(*self))
+  }
+}
+
+/// Pushes the time as a float number of seconds since the Unix epoch, negative
+/// for times before it.
+impl ToLua for SystemTime {
+  fn to_lua(&self, state: &mut State) {
+    match self.duration_since(UNIX_EPOCH) {
+      Ok(after) => state.push_number(duration_to_secs(after)),
+      Err(before) => state.push_number(-duration_to_secs(before.duration())),
+    }
+  }
+}
+
 //#[unstable(reason="this is an experimental trait")]
 impl<T> ToLua for *mut T {
   fn to_lua(&self, state: &mut State) {
@@ -92,6 +138,77 @@ impl<T: ToLua> ToLua for Option<T> {
   }
 }
 
+/// Trait for types that push any number of values onto the stack of a Lua
+/// state, such as the arguments to a function call.
+///
+/// This is implemented for every `ToLua` type, which pushes one value, for
+/// tuples of `ToLua` values of up to 8 elements, which push their elements
+/// from left to right, and for `Result`. The returned count makes it usable
+/// as the return value of a native function:
+///
+/// ```ignore
+/// return (sum, product).push_multi(&mut state);
+/// ```
+pub trait ToLuaMulti {
+  /// Pushes the values represented by `Self` onto the stack of a Lua state
+  /// and returns the number of values pushed.
+  fn push_multi(&self, state: &mut State) -> c_int;
+}
+
+/// Any single value pushes exactly one stack slot.
+impl<T: ToLua> ToLuaMulti for T {
+  fn push_multi(&self, state: &mut State) -> c_int {
+    self.to_lua(state);
+    1
+  }
+}
+
+macro_rules! count_idents {
+  () => { 0 };
+  ($head:ident $($tail:ident)*) => { 1 + count_idents!($($tail)*) };
+}
+
+macro_rules! impl_to_lua_multi {
+  ($($name:ident)*) => {
+    impl<$($name: ToLua),*> ToLuaMulti for ($($name,)*) {
+      #[allow(non_snake_case, unused_variables)]
+      fn push_multi(&self, state: &mut State) -> c_int {
+        let &($(ref $name,)*) = self;
+        $($name.to_lua(state);)*
+        count_idents!($($name)*)
+      }
+    }
+  }
+}
+
+impl_to_lua_multi!();
+impl_to_lua_multi!(A);
+impl_to_lua_multi!(A B);
+impl_to_lua_multi!(A B C);
+impl_to_lua_multi!(A B C D);
+impl_to_lua_multi!(A B C D E);
+impl_to_lua_multi!(A B C D E F);
+impl_to_lua_multi!(A B C D E F G);
+impl_to_lua_multi!(A B C D E F G H);
+
+/// Follows the Lua convention for functions that can fail: `Ok(value)` pushes
+/// the value, while `Err(e)` pushes `nil` followed by the error.
+impl<T: ToLua, E: ToLua> ToLuaMulti for Result<T, E> {
+  fn push_multi(&self, state: &mut State) -> c_int {
+    match *self {
+      Ok(ref value) => {
+        value.to_lua(state);
+        1
+      }
+      Err(ref err) => {
+        state.push_nil();
+        err.to_lua(state);
+        2
+      }
+    }
+  }
+}
+
 /// Trait for types that can be taken from the Lua stack.
 ///
 /// It is important that implementors of this trait ensure that `from_lua`
@@ -102,9 +219,34 @@ pub trait FromLua: Sized {
   fn from_lua(state: &mut State, index: Index) -> Option<Self>;
 }
 
+/// Converts any value with `luaL_tolstring`, so `nil`, booleans and values
+/// with a `__tostring` metamethod convert as well. The value itself is left
+/// untouched (numbers are not converted in place, so keys may be converted
+/// during a traversal) and the intermediate string is popped again, leaving
+/// the stack unchanged. Returns `None` if the result is not valid UTF-8.
 impl FromLua for String {
   fn from_lua(state: &mut State, index: Index) -> Option<String> {
-    state.to_str(index).map(ToOwned::to_owned)
+    let value = state.to_str(index).map(ToOwned::to_owned);
+    state.pop(1);
+    value
+  }
+}
+
+/// Converts a string holding exactly one `char`. Numbers are not converted.
+impl FromLua for char {
+  fn from_lua(state: &mut State, index: Index) -> Option<char> {
+    if state.type_of(index) != Some(Type::String) {
+      return None;
+    }
+    let s = match state.to_str_in_place(index) {
+      Some(s) => s,
+      None => return None,
+    };
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+      (Some(c), None) => Some(c),
+      _ => None,
+    }
   }
 }
 
@@ -114,6 +256,44 @@ impl FromLua for Vec<u8> {
   }
 }
 
+impl FromLua for LuaValue {
+  fn from_lua(state: &mut State, index: Index) -> Option<LuaValue> {
+    Some(state.to_value(index))
+  }
+}
+
+/// Reads a non-negative number of seconds, as pushed by `ToLua`.
+impl FromLua for Duration {
+  fn from_lua(state: &mut State, index: Index) -> Option<Duration> {
+    Number::from_lua(state, index).and_then(secs_to_duration)
+  }
+}
+
+/// Reads a number of seconds since the Unix epoch, as pushed by `ToLua`.
+impl FromLua for SystemTime {
+  fn from_lua(state: &mut State, index: Index) -> Option<SystemTime> {
+    Number::from_lua(state, index).and_then(|secs| {
+      if secs >= 0.0 {
+        secs_to_duration(secs).and_then(|after| UNIX_EPOCH.checked_add(after))
+      } else {
+        secs_to_duration(-secs).and_then(|before| UNIX_EPOCH.checked_sub(before))
+      }
+    })
+  }
+}
+
+fn duration_to_secs(duration: Duration) -> Number {
+  duration.as_secs() as Number + duration.subsec_nanos() as Number * 1e-9
+}
+
+fn secs_to_duration(secs: Number) -> Option<Duration> {
+  if secs >= 0.0 && secs < u64::max_value() as Number {
+    Some(Duration::new(secs as u64, ((secs - secs.floor()) * 1e9) as u32))
+  } else {
+    None
+  }
+}
+
 impl FromLua for Integer {
   fn from_lua(state: &mut State, index: Index) -> Option<Integer> {
     if state.is_integer(index) {
@@ -154,3 +334,119 @@ impl FromLua for Function {
     }
   }
 }
+
+//#[unstable(reason="this is an experimental trait")]
+impl<T: FromLua> FromLua for Option<T> {
+  fn from_lua(state: &mut State, index: Index) -> Option<Option<T>> {
+    if state.is_none_or_nil(index) {
+      Some(None)
+    } else {
+      T::from_lua(state, index).map(Some)
+    }
+  }
+}
+
+/// Trait for types that can be taken from several consecutive slots of the
+/// Lua stack, such as the results of a function call.
+///
+/// This is implemented for tuples of `FromLua` values of up to 8 elements.
+pub trait FromLuaMulti: Sized {
+  /// The number of stack slots read by `from_lua_multi`.
+  const COUNT: c_int;
+
+  /// Converts the `COUNT` values starting at `index` to a value of type
+  /// `Option<Self>`. Returns `None` if any of the values fails to convert.
+  fn from_lua_multi(state: &mut State, index: Index) -> Option<Self>;
+}
+
+macro_rules! impl_from_lua_multi {
+  ($($name:ident)*) => {
+    impl<$($name: FromLua),*> FromLuaMulti for ($($name,)*) {
+      const COUNT: c_int = count_idents!($($name)*);
+
+      #[allow(unused_variables, unused_mut, unused_assignments)]
+      fn from_lua_multi(state: &mut State, index: Index) -> Option<Self> {
+        let mut index = state.abs_index(index);
+        Some(($({
+          let value = match $name::from_lua(state, index) {
+            Some(value) => value,
+            None => return None,
+          };
+          index += 1;
+          value
+        },)*))
+      }
+    }
+  }
+}
+
+impl_from_lua_multi!();
+impl_from_lua_multi!(A);
+impl_from_lua_multi!(A B);
+impl_from_lua_multi!(A B C);
+impl_from_lua_multi!(A B C D);
+impl_from_lua_multi!(A B C D E);
+impl_from_lua_multi!(A B C D E F);
+impl_from_lua_multi!(A B C D E F G);
+impl_from_lua_multi!(A B C D E F G H);
+
+/// Converts a tuple to and from a single Lua array table: `Array((1, "a"))`
+/// pushes `{1, "a"}`, and reading a table as `Array<(A, B)>` converts `t[1]`
+/// and `t[2]`, ignoring any further elements. Implemented for tuples of up to
+/// 8 elements.
+///
+/// Plain tuples are `ToLuaMulti` values instead, which push their elements as
+/// separate stack slots (for example as function arguments); this wrapper
+/// exists because a tuple cannot be both. Reading fails if the value is not a
+/// table or an element does not convert.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Array<T>(pub T);
+
+macro_rules! impl_array {
+  ($($name:ident)*) => {
+    impl<$($name: ToLua),*> ToLua for Array<($($name,)*)> {
+      #[allow(non_snake_case, unused_variables, unused_mut, unused_assignments)]
+      fn to_lua(&self, state: &mut State) {
+        let &Array(($(ref $name,)*)) = self;
+        state.create_table(count_idents!($($name)*), 0);
+        let mut i = 1;
+        $(
+          $name.to_lua(state);
+          state.raw_seti(-2, i);
+          i += 1;
+        )*
+      }
+    }
+
+    impl<$($name: FromLua),*> FromLua for Array<($($name,)*)> {
+      #[allow(unused_variables, unused_mut, unused_assignments)]
+      fn from_lua(state: &mut State, index: Index) -> Option<Self> {
+        if !state.is_table(index) {
+          return None;
+        }
+        let index = state.abs_index(index);
+        let mut i = 1;
+        Some(Array(($({
+          state.raw_geti(index, i);
+          let value = $name::from_lua(state, -1);
+          state.pop(1);
+          i += 1;
+          match value {
+            Some(value) => value,
+            None => return None,
+          }
+        },)*)))
+      }
+    }
+  }
+}
+
+impl_array!();
+impl_array!(A);
+impl_array!(A B);
+impl_array!(A B C);
+impl_array!(A B C D);
+impl_array!(A B C D E);
+impl_array!(A B C D E F);
+impl_array!(A B C D E F G);
+impl_array!(A B C D E F G H);
@@ -0,0 +1,151 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2014 J.C. Moyer
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! Implements a runner that drives a Lua coroutine from Rust.
+
+use std::marker::PhantomData;
+
+use ::{State, ThreadStatus, LuaValue, ToLuaMulti, REGISTRYINDEX, Type};
+
+// Registry key of the table that keeps running coroutines from being
+// collected; only its address matters.
+static ANCHORS_KEY: u8 = 0;
+
+/// The outcome of resuming a `Coroutine`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CoResult {
+  /// The coroutine yielded these values and can be resumed again.
+  Yielded(Vec<LuaValue>),
+  /// The coroutine's function returned these values; the coroutine is dead.
+  Returned(Vec<LuaValue>),
+  /// The coroutine raised an error, or could not be resumed. Error objects
+  /// that are not strings or numbers are described by their type, since
+  /// `__tostring` cannot be called on the failed thread.
+  Error(String),
+}
+
+/// A Lua coroutine driven from Rust.
+///
+/// The thread is kept alive until the `Coroutine` is dropped. Like `Thread`,
+/// a `Coroutine` borrows the state it was created from, so it cannot outlive
+/// that state:
+///
+/// ```compile_fail
+/// # use lua::{State, Coroutine};
+/// let mut state = State::new();
+/// state.load_string("return 1");
+/// let co = Coroutine::new(&mut state);
+/// drop(state);
+/// drop(co);
+/// ```
+pub struct Coroutine<'a> {
+  thread: State,
+  dead: bool,
+  _parent: PhantomData<&'a mut State>,
+}
+
+impl<'a> Coroutine<'a> {
+  /// Pops the function on the top of the stack of `state` and creates a new
+  /// coroutine that runs it. `state` stays borrowed until the coroutine is
+  /// dropped.
+  pub fn new(state: &'a mut State) -> Coroutine<'a> {
+    let mut thread = state.new_thread();
+    // anchors[thread] = thread, then pop the thread pushed by new_thread
+    let key = thread.as_ptr() as *const u8;
+    if state.raw_getp(REGISTRYINDEX, &ANCHORS_KEY as *const u8) != Type::Table {
+      state.pop(1);
+      state.new_table();
+      state.push_value(-1);
+      state.raw_setp(REGISTRYINDEX, &ANCHORS_KEY as *const u8);
+    }
+    state.push_value(-2);
+    state.raw_setp(-2, key);
+    state.pop(2);
+    state.xmove(&mut thread, 1);
+    Coroutine {
+      thread: thread,
+      dead: false,
+      _parent: PhantomData,
+    }
+  }
+
+  /// Resumes the coroutine, passing `args` to it. On the first resume they
+  /// are the arguments of the function; afterwards they are the results of
+  /// the `coroutine.yield` call that suspended it. Yielded and returned
+  /// values are copied with `State::to_value`.
+  ///
+  /// Once the coroutine has returned or raised an error, further resumes
+  /// fail with `cannot resume dead coroutine`, as in `coroutine.resume`.
+  pub fn resume_with<A: ToLuaMulti>(&mut self, args: A) -> CoResult {
+    // lua_resume does not check for dead coroutines itself
+    if self.dead {
+      return CoResult::Error("cannot resume dead coroutine".to_owned());
+    }
+    let nargs = args.push_multi(&mut self.thread);
+    match self.thread.resume(None, nargs) {
+      ThreadStatus::Yield => CoResult::Yielded(self.take_values()),
+      ThreadStatus::Ok => {
+        self.dead = true;
+        CoResult::Returned(self.take_values())
+      }
+      _ => {
+        self.dead = true;
+        // the thread is in an error state, so metamethods such as __tostring
+        // cannot be called on it
+        let msg = self.thread.to_bytes_in_place(-1).map(|bytes| String::from_utf8_lossy(bytes).into_owned());
+        let msg = msg.unwrap_or_else(|| {
+          format!("(error object is a {} value)", self.thread.typename_at(-1))
+        });
+        self.thread.set_top(0);
+        CoResult::Error(msg)
+      }
+    }
+  }
+
+  /// Returns true if the coroutine has returned or raised an error.
+  pub fn is_dead(&self) -> bool {
+    self.dead
+  }
+
+  /// Returns the thread the coroutine runs on.
+  pub fn thread(&mut self) -> &mut State {
+    &mut self.thread
+  }
+
+  fn take_values(&mut self) -> Vec<LuaValue> {
+    let top = self.thread.get_top();
+    let values = (1..top + 1).map(|i| self.thread.to_value(i)).collect();
+    self.thread.set_top(0);
+    values
+  }
+}
+
+impl<'a> Drop for Coroutine<'a> {
+  fn drop(&mut self) {
+    let key = self.thread.as_ptr() as *const u8;
+    if self.thread.raw_getp(REGISTRYINDEX, &ANCHORS_KEY as *const u8) == Type::Table {
+      self.thread.push_nil();
+      self.thread.raw_setp(-2, key);
+    }
+    self.thread.pop(1);
+  }
+}
@@ -23,5 +23,6 @@
 //! High level bindings to Lua.
 
 pub mod convert;
+pub mod coroutine;
 pub mod state;
 
@@ -24,11 +24,16 @@ use ffi;
 use ffi::{lua_State, lua_Debug};
 
 use libc::{c_int, c_void, c_char, size_t};
-use std::{mem, ptr, str, slice, any};
+use std::{cmp, fmt, io, mem, ptr, str, slice, any};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::ffi::{CString, CStr};
-use std::ops::DerefMut;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
 use std::sync::Mutex;
-use super::convert::{ToLua, FromLua};
+use std::time::{Duration, Instant};
+use super::convert::{ToLua, ToLuaMulti, FromLua, FromLuaMulti};
 
 use ::{
   Number,
@@ -58,6 +63,17 @@ pub enum Arithmetic {
   BNot = ffi::LUA_OPBNOT as isize,
 }
 
+impl Arithmetic {
+  /// Returns the number of operands popped by this operation: 1 for `Unm` and
+  /// `BNot`, and 2 for everything else.
+  pub fn operands(self) -> c_int {
+    match self {
+      Arithmetic::Unm | Arithmetic::BNot => 1,
+      _ => 2,
+    }
+  }
+}
+
 /// Comparison operations for `lua_compare`.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Comparison {
@@ -108,6 +124,103 @@ impl ThreadStatus {
       ThreadStatus::Yield => false,
     }
   }
+
+  /// Converts the status into a `Result`, with `Ok` and `Yield` mapping to
+  /// `Ok(())` and every error status to `Err`. The error message stays on the
+  /// stack; see `pcall_traceback` and similar helpers to retrieve it.
+  pub fn into_result(self) -> Result<(), ThreadStatus> {
+    if self.is_err() {
+      Err(self)
+    } else {
+      Ok(())
+    }
+  }
+}
+
+impl From<ThreadStatus> for Result<(), ThreadStatus> {
+  fn from(status: ThreadStatus) -> Result<(), ThreadStatus> {
+    status.into_result()
+  }
+}
+
+/// Errors reported by the checked stack manipulation functions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackError {
+  /// The stack holds fewer values than the operation requires.
+  Underflow { required: c_int, available: c_int },
+  /// `index` does not refer to a value on a stack of `top` values. Pseudo
+  /// indices are rejected as well.
+  InvalidIndex { index: Index, top: Index },
+  /// `count` exceeds the `available` number of values it applies to.
+  CountOutOfRange { count: c_int, available: c_int },
+  /// The stack cannot grow by `requested` slots, either because it would
+  /// exceed the maximum stack size or because memory ran out.
+  Overflow { requested: c_int },
+}
+
+/// Errors reported by `try_yield`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum YieldError {
+  /// The running coroutine cannot yield: the state is the main thread, or a
+  /// non-yieldable C call is on the stack.
+  NotYieldable,
+}
+
+/// Reasons a value could not be converted by `to_integer_checked`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntError {
+  /// The value is neither a number nor a string convertible to a number.
+  NotANumber,
+  /// The value is a number, but has no exact integer representation (for
+  /// example `3.5`, or a float outside the range of `Integer`).
+  NoIntegerRepresentation,
+}
+
+/// A value passed to or returned from `pack` and `unpack`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PackValue {
+  /// For the integer formats (`b`, `h`, `i`, `j`, `l`, `T`, ...).
+  Integer(Integer),
+  /// For the floating point formats (`f`, `d`, `n`).
+  Number(Number),
+  /// For the string formats (`s`, `z`, `c`).
+  Bytes(Vec<u8>),
+}
+
+/// An owned copy of a Lua value, produced by `to_value`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LuaValue {
+  Nil,
+  Bool(bool),
+  Integer(Integer),
+  Number(Number),
+  /// A string, which may hold arbitrary bytes.
+  Str(Vec<u8>),
+  /// The raw key-value pairs of a table, in traversal order.
+  Table(Vec<(LuaValue, LuaValue)>),
+  /// A Lua or native function.
+  Function,
+  /// A value with no owned representation (userdata, threads), or a table
+  /// nested deeper than the requested depth.
+  Other(Type),
+}
+
+/// The subtype of a Lua number, as reported by `number_kind`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NumberKind {
+  Integer,
+  Float,
+}
+
+/// Which references of a table created by `create_weak_table` are weak.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeakMode {
+  /// Weak keys (`__mode = "k"`), as for an ephemeron table.
+  Keys,
+  /// Weak values (`__mode = "v"`).
+  Values,
+  /// Weak keys and values (`__mode = "kv"`).
+  Both,
 }
 
 /// Options for the Lua garbage collector.
@@ -238,6 +351,171 @@ impl Reference {
   }
 }
 
+// Metatable key of the `TypeId` stored by `register_type`.
+static TYPE_TAG_KEY: u8 = 0;
+
+// Registry key of the `AppData` map used by `set_app_data`.
+static APP_DATA_KEY: u8 = 0;
+
+type AppData = HashMap<any::TypeId, Box<dyn any::Any + Send>>;
+
+// Registry key of the `HookFn` called by hooks installed with `set_hook_fn`.
+static HOOK_KEY: u8 = 0;
+
+type HookFn = Box<dyn FnMut(&mut State, &lua_Debug) + Send>;
+
+// Stands in for `nil` in registry slots owned by a `RegistryKey`, because
+// `luaL_ref` cannot reserve a slot for `nil`.
+static REGISTRY_NIL: u8 = 0;
+
+/// A slot in the registry holding a single value, allocated with `reference`.
+///
+/// Unlike a bare `Reference`, a key always refers to the registry table and
+/// may hold any value, including `nil`. Freeing the slot needs access to the
+/// state, so keys are not released on drop; call `unref` once the key is no
+/// longer needed, or the slot leaks for the lifetime of the state.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct RegistryKey(Reference);
+
+impl RegistryKey {
+  /// Pops the value on the top of the stack and stores it in a new registry
+  /// slot.
+  pub fn new(state: &mut State) -> RegistryKey {
+    RegistryKey::replace_nil(state);
+    RegistryKey(state.reference(REGISTRYINDEX))
+  }
+
+  /// Pops the value on the top of the stack and stores it in this slot,
+  /// replacing the previous value.
+  pub fn set_from_stack(&self, state: &mut State) {
+    RegistryKey::replace_nil(state);
+    state.raw_seti(REGISTRYINDEX, self.0.value() as Integer);
+  }
+
+  /// Pushes the value stored in this slot onto the stack.
+  pub fn push(&self, state: &mut State) {
+    state.raw_geti(REGISTRYINDEX, self.0.value() as Integer);
+    if state.to_userdata(-1) as *const u8 == &REGISTRY_NIL as *const u8 {
+      state.pop(1);
+      state.push_nil();
+    }
+  }
+
+  /// Frees the registry slot, allowing its value to be collected.
+  pub fn unref(self, state: &mut State) {
+    state.unreference(REGISTRYINDEX, self.0);
+  }
+
+  /// Returns the underlying reference.
+  pub fn reference(&self) -> Reference {
+    self.0
+  }
+  fn replace_nil(state: &mut State) {
+    if state.is_nil(-1) {
+      state.pop(1);
+      unsafe { state.push_light_userdata(&REGISTRY_NIL as *const u8 as *mut u8) };
+    }
+  }
+}
+
+/// Information about a function, copied out of a `lua_Debug` so that it stays
+/// valid after the Lua strings it refers to are collected.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugInfo {
+  source: Option<String>,
+  short_src: String,
+  what: Option<String>,
+  name: Option<String>,
+  name_what: Option<String>,
+  current_line: c_int,
+  line_defined: c_int,
+  last_line_defined: c_int,
+}
+
+impl<'a> From<&'a lua_Debug> for DebugInfo {
+  fn from(ar: &'a lua_Debug) -> DebugInfo {
+    DebugInfo {
+      source: unsafe { copy_c_str(ar.source) },
+      short_src: unsafe { copy_c_str(ar.short_src.as_ptr()) }.unwrap_or_default(),
+      what: unsafe { copy_c_str(ar.what) },
+      name: unsafe { copy_c_str(ar.name) },
+      name_what: unsafe { copy_c_str(ar.namewhat) }.and_then(|s| if s.is_empty() { None } else { Some(s) }),
+      current_line: ar.currentline,
+      line_defined: ar.linedefined,
+      last_line_defined: ar.lastlinedefined,
+    }
+  }
+}
+
+impl DebugInfo {
+  /// The source of the chunk that created the function, as described for the
+  /// `source` field of `lua_Debug`. Requires the `S` option.
+  pub fn source(&self) -> Option<&str> {
+    self.source.as_ref().map(|s| &s[..])
+  }
+
+  /// The line where the definition of the function starts. Requires the `S`
+  /// option.
+  pub fn line_defined(&self) -> c_int {
+    self.line_defined
+  }
+
+  /// A printable version of `source`, for use in error messages. Requires the
+  /// `S` option.
+  pub fn short_src(&self) -> &str {
+    &self.short_src
+  }
+
+  /// The line being executed by the function, or -1 when no line information
+  /// is available. Requires the `l` option.
+  pub fn current_line(&self) -> c_int {
+    self.current_line
+  }
+
+  /// The line where the definition of the function ends. Requires the `S`
+  /// option.
+  pub fn last_line_defined(&self) -> c_int {
+    self.last_line_defined
+  }
+
+  /// `"Lua"`, `"C"` or `"main"`. Requires the `S` option.
+  pub fn what(&self) -> Option<&str> {
+    self.what.as_ref().map(|s| &s[..])
+  }
+
+  /// A reasonable name for the function, if Lua could find one. Requires the
+  /// `n` option, and is always `None` when the function is not the one being
+  /// executed in a call frame.
+  pub fn name(&self) -> Option<&str> {
+    self.name.as_ref().map(|s| &s[..])
+  }
+
+  /// Explains `name`: `"global"`, `"local"`, `"method"`, `"field"`,
+  /// `"upvalue"`, or `None` when no name was found. Requires the `n` option.
+  pub fn name_what(&self) -> Option<&str> {
+    self.name_what.as_ref().map(|s| &s[..])
+  }
+}
+
+#[cfg(unix)]
+fn path_to_c_string(path: &Path) -> CString {
+  use std::os::unix::ffi::OsStrExt;
+  CString::new(path.as_os_str().as_bytes()).unwrap()
+}
+
+#[cfg(not(unix))]
+fn path_to_c_string(path: &Path) -> CString {
+  CString::new(path.to_string_lossy().into_owned()).unwrap()
+}
+
+unsafe fn copy_c_str(ptr: *const c_char) -> Option<String> {
+  if ptr.is_null() {
+    None
+  } else {
+    Some(String::from_utf8_lossy(CStr::from_ptr(ptr).to_bytes()).into_owned())
+  }
+}
+
 bitflags! {
   #[doc="Hook point masks for `lua_sethook`."]
   flags HookMask: c_int {
@@ -270,7 +548,38 @@ unsafe extern fn continue_func<F>(st: *mut lua_State, status: c_int, ctx: ffi::l
   mem::transmute::<_, Box<F>>(ctx)(&mut State::from_ptr(st), ThreadStatus::from_c_int(status))
 }
 
-/// Box for extra data.
+// Lua calls the panic function and then `abort()`s; panicking here at least
+// routes the error message through the Rust panic hook before that happens.
+#[allow(non_snake_case)]
+unsafe extern "C" fn panic_func(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  let msg = state.pop_error();
+  panic!("unprotected error in call to Lua API ({})", msg)
+}
+
+// Message handler used by `pcall_traceback`, modeled after the one in lua.c.
+#[allow(non_snake_case)]
+unsafe extern "C" fn traceback_handler(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  let msg = match state.to_bytes_in_place(1) {
+    Some(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+    None => {
+      if state.call_meta(1, "__tostring") && state.type_of(-1) == Some(Type::String) {
+        return 1;
+      }
+      format!("(error object is a {} value)", state.typename_at(1))
+    }
+  };
+  let mut thread = State::from_ptr(L);
+  state.traceback(&mut thread, &msg, 1);
+  1
+}
+
+/// Box for extra data, attached to a state with `set_extra`.
+///
+/// The extra data is kept in the extra space of the main thread (see
+/// `lua_getextraspace`) and is shared with every thread created from it by
+/// `new_thread`. It is dropped together with the owning `State`.
 pub type Extra = Box<any::Any + 'static + Send>;
 type ExtraHolder = *mut *mut Mutex<Option<Extra>>;
 
@@ -318,6 +627,31 @@ unsafe extern fn alloc_func(_: *mut c_void, ptr: *mut c_void, old_size: size_t,
   }
 }
 
+// Memory usage tracked by `counting_alloc_func`, owned by the Lua state.
+struct AllocStats {
+  allocated: usize,
+  peak: usize,
+  limit: usize,
+}
+
+unsafe extern "C" fn counting_alloc_func(ud: *mut c_void, ptr: *mut c_void, old_size: size_t, new_size: size_t) -> *mut c_void {
+  let stats = &mut *(ud as *mut AllocStats);
+  // when ptr is null, old_size is a type indicator rather than a size
+  let old_size = if ptr.is_null() { 0 } else { old_size as usize };
+  // refuse to grow past the limit; Lua turns this into a memory error
+  if new_size as usize > old_size && stats.allocated - old_size + new_size as usize > stats.limit {
+    return ptr::null_mut();
+  }
+  let res = alloc_func(ptr::null_mut(), ptr, old_size as size_t, new_size);
+  if new_size == 0 || !res.is_null() {
+    stats.allocated = stats.allocated - old_size + new_size as usize;
+    if stats.allocated > stats.peak {
+      stats.peak = stats.allocated;
+    }
+  }
+  res
+}
+
 /// An idiomatic, Rust wrapper around `lua_State`.
 ///
 /// Function names adhere to Rust naming conventions. Most of the time, this
@@ -336,21 +670,103 @@ pub struct State {
   owned: bool
 }
 
+// A Lua state may be used from any thread as long as only one thread uses it
+// at a time. Moving a `State` transfers that access, and `&mut self` on every
+// method keeps a single `State` from being used concurrently. `State` is
+// deliberately not `Sync`.
+//
+// Threads created with `new_thread` and wrappers from `from_ptr` share the
+// underlying global state with their parent; sending one of them to another
+// thread while the parent is still in use elsewhere is a data race that the
+// type system cannot catch.
 unsafe impl Send for State {}
 
 impl State {
   /// Initializes a new Lua state. This function does not open any libraries
-  /// by default. Calls `lua_newstate` internally.
+  /// by default. Calls `lua_newstate` internally and installs the panic
+  /// function described in `set_rust_panic_handler`.
   pub fn new() -> State {
     unsafe {
       let state = ffi::lua_newstate(Some(alloc_func), ptr::null_mut());
-      let extra_ptr = ffi::lua_getextraspace(state) as ExtraHolder;
-      let mutex = Box::new(Mutex::new(None));
-      *extra_ptr = Box::into_raw(mutex);
+      State::init_owned(state)
+    }
+  }
+
+  /// Initializes a new Lua state with `luaL_newstate`, which uses the C
+  /// library's `realloc` and `free` instead of the Rust allocator, and skips
+  /// the extra data setup done by `new`. Whether this is faster than `new`
+  /// depends on the allocators; see the `state-bench` example.
+  ///
+  /// States created this way do not support extra data: `set_extra`,
+  /// `with_extra` and `with_extra_typed` panic. `set_app_data` works as
+  /// usual. The panic function is installed as in `new`.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the state cannot be created because memory ran out.
+  pub fn new_native() -> State {
+    unsafe {
+      let state = ffi::luaL_newstate();
+      assert!(!state.is_null(), "failed to create a Lua state");
+      *(ffi::lua_getextraspace(state) as ExtraHolder) = ptr::null_mut();
+      ffi::lua_atpanic(state, Some(panic_func));
       State { L: state, owned: true }
     }
   }
 
+  /// Initializes a new Lua state that uses the allocator `f` with the opaque
+  /// pointer `ud`. Returns `None` if the state could not be created because
+  /// the allocator failed. Otherwise behaves like `State::new`.
+  ///
+  /// This function is unsafe because `f` must behave as described in the
+  /// documentation for `lua_Alloc`, and `ud` must remain valid until the state
+  /// is closed.
+  pub unsafe fn with_allocator(f: Allocator, ud: *mut c_void) -> Option<State> {
+    let state = ffi::lua_newstate(f, ud);
+    if state.is_null() {
+      None
+    } else {
+      Some(State::init_owned(state))
+    }
+  }
+
+  /// Initializes a new Lua state like `State::new` that additionally keeps
+  /// track of the memory it allocates. Use `allocated_bytes` and `peak_bytes`
+  /// to query the totals.
+  ///
+  /// Unlike `gc_count_bytes`, which is maintained by the garbage collector,
+  /// these totals are recorded by the allocator itself.
+  pub fn new_counting() -> State {
+    State::with_memory_limit(usize::MAX)
+  }
+
+  /// Initializes a new Lua state like `State::new_counting` that refuses to
+  /// allocate more than `max_bytes` bytes in total. Allocations past the limit
+  /// fail, which Lua reports as a memory error (`ThreadStatus::MemoryError`
+  /// from `pcall`).
+  ///
+  /// # Panics
+  ///
+  /// Panics if `max_bytes` is too small to create the state itself.
+  pub fn with_memory_limit(max_bytes: usize) -> State {
+    let stats = Box::new(AllocStats { allocated: 0, peak: 0, limit: max_bytes });
+    let ud = Box::into_raw(stats) as *mut c_void;
+    match unsafe { State::with_allocator(Some(counting_alloc_func), ud) } {
+      Some(state) => state,
+      None => {
+        drop(unsafe { Box::from_raw(ud as *mut AllocStats) });
+        panic!("failed to allocate Lua state")
+      }
+    }
+  }
+
+  #[allow(non_snake_case)]
+  unsafe fn init_owned(L: *mut lua_State) -> State {
+    let state = State::from_ptr_owned(L);
+    ffi::lua_atpanic(L, Some(panic_func));
+    state
+  }
+
   /// Constructs a wrapper `State` from a raw pointer. This is suitable for use
   /// inside of native functions that accept a `lua_State` to obtain a wrapper.
   #[allow(non_snake_case)]
@@ -358,6 +774,27 @@ impl State {
     State { L: L, owned: false }
   }
 
+  /// Constructs a wrapper `State` that takes ownership of a main thread
+  /// created outside of this crate, for example with `luaL_newstate` in C
+  /// code. The state is closed with `lua_close` when the wrapper is dropped.
+  ///
+  /// The extra space of the state is overwritten to hold the data used by
+  /// `set_extra`; the panic function is left unchanged.
+  ///
+  /// # Safety
+  ///
+  /// `L` must be a valid main thread (not a thread created by `new_thread`)
+  /// that nothing else will close. No other owning `State` may wrap the same
+  /// pointer, and non-owning wrappers obtained through `from_ptr` must not
+  /// be used after this one is dropped.
+  #[allow(non_snake_case)]
+  pub unsafe fn from_ptr_owned(L: *mut lua_State) -> State {
+    let extra_ptr = ffi::lua_getextraspace(L) as ExtraHolder;
+    let mutex = Box::new(Mutex::new(None));
+    *extra_ptr = Box::into_raw(mutex);
+    State { L: L, owned: true }
+  }
+
   /// Returns an unsafe pointer to the wrapped `lua_State`.
   pub fn as_ptr(&self) -> *mut lua_State {
     self.L
@@ -385,70 +822,128 @@ impl State {
     self.pop(1);  /* remove lib */
   }
 
-  /// Maps to `luaopen_base`.
-  pub fn open_base(&mut self) -> c_int {
-    unsafe { ffi::luaopen_base(self.L) }
+  /// Loads each of the given built-in libraries. Unlike `open_libs`, this
+  /// makes it possible to expose only a subset of the standard libraries, for
+  /// example to untrusted code.
+  pub fn load_libraries(&mut self, libs: &[Library]) {
+    for &lib in libs {
+      self.load_library(lib);
+    }
+  }
+
+  /// Opens a subset of the standard libraries that is suitable for running
+  /// untrusted code: `table`, `string`, `math`, `utf8`, and the base library
+  /// without `dofile`, `loadfile`, `load`, and `collectgarbage`. The `io`,
+  /// `os`, `package`, `debug`, and `coroutine` libraries are not opened.
+  ///
+  /// The remaining base library globals are `assert`, `error`,
+  /// `getmetatable`, `ipairs`, `next`, `pairs`, `pcall`, `print`, `rawequal`,
+  /// `rawget`, `rawlen`, `rawset`, `select`, `setmetatable`, `tonumber`,
+  /// `tostring`, `type`, `xpcall`, `_G`, and `_VERSION`.
+  pub fn open_safe_libs(&mut self) {
+    self.load_libraries(&[
+      Library::Base, Library::Table, Library::String, Library::Math, Library::Utf8
+    ]);
+    for name in &["dofile", "loadfile", "load", "collectgarbage"] {
+      self.push_nil();
+      self.set_global(name);
+    }
+  }
+
+  /// Opens the library provided by `luaopen_base` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::Base)`.
+  pub fn open_base(&mut self) {
+    self.load_library(Library::Base)
   }
 
-  /// Maps to `luaopen_coroutine`.
-  pub fn open_coroutine(&mut self) -> c_int {
-    unsafe { ffi::luaopen_coroutine(self.L) }
+  /// Opens the library provided by `luaopen_coroutine` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::Coroutine)`.
+  pub fn open_coroutine(&mut self) {
+    self.load_library(Library::Coroutine)
   }
 
-  /// Maps to `luaopen_table`.
-  pub fn open_table(&mut self) -> c_int {
-    unsafe { ffi::luaopen_table(self.L) }
+  /// Opens the library provided by `luaopen_table` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::Table)`.
+  pub fn open_table(&mut self) {
+    self.load_library(Library::Table)
   }
 
-  /// Maps to `luaopen_io`.
-  pub fn open_io(&mut self) -> c_int {
-    unsafe { ffi::luaopen_io(self.L) }
+  /// Opens the library provided by `luaopen_io` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::Io)`.
+  pub fn open_io(&mut self) {
+    self.load_library(Library::Io)
   }
 
-  /// Maps to `luaopen_os`.
-  pub fn open_os(&mut self) -> c_int {
-    unsafe { ffi::luaopen_os(self.L) }
+  /// Opens the library provided by `luaopen_os` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::Os)`.
+  pub fn open_os(&mut self) {
+    self.load_library(Library::Os)
   }
 
-  /// Maps to `luaopen_string`.
-  pub fn open_string(&mut self) -> c_int {
-    unsafe { ffi::luaopen_string(self.L) }
+  /// Opens the library provided by `luaopen_string` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::String)`.
+  pub fn open_string(&mut self) {
+    self.load_library(Library::String)
   }
 
-  /// Maps to `luaopen_utf8`.
-  pub fn open_utf8(&mut self) -> c_int {
-    unsafe { ffi::luaopen_utf8(self.L) }
+  /// Opens the library provided by `luaopen_utf8` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::Utf8)`.
+  pub fn open_utf8(&mut self) {
+    self.load_library(Library::Utf8)
   }
 
-  /// Maps to `luaopen_bit32`.
-  pub fn open_bit32(&mut self) -> c_int {
-    unsafe { ffi::luaopen_bit32(self.L) }
+  /// Opens the library provided by `luaopen_bit32` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::Bit32)`.
+  pub fn open_bit32(&mut self) {
+    self.load_library(Library::Bit32)
   }
 
-  /// Maps to `luaopen_math`.
-  pub fn open_math(&mut self) -> c_int {
-    unsafe { ffi::luaopen_math(self.L) }
+  /// Opens the library provided by `luaopen_math` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::Math)`.
+  pub fn open_math(&mut self) {
+    self.load_library(Library::Math)
   }
 
-  /// Maps to `luaopen_debug`.
-  pub fn open_debug(&mut self) -> c_int {
-    unsafe { ffi::luaopen_debug(self.L) }
+  /// Opens the library provided by `luaopen_debug` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::Debug)`.
+  pub fn open_debug(&mut self) {
+    self.load_library(Library::Debug)
   }
 
-  /// Maps to `luaopen_package`.
-  pub fn open_package(&mut self) -> c_int {
-    unsafe { ffi::luaopen_package(self.L) }
+  /// Opens the library provided by `luaopen_package` through `luaL_requiref`,
+  /// which also sets its global. Same as `load_library(Library::Package)`.
+  pub fn open_package(&mut self) {
+    self.load_library(Library::Package)
   }
 
   /// Maps to `luaL_dofile`.
-  pub fn do_file(&mut self, filename: &str) -> ThreadStatus {
-    let c_str = CString::new(filename).unwrap();
+  ///
+  /// See `load_file` for how `filename` is converted.
+  pub fn do_file<P: AsRef<Path>>(&mut self, filename: P) -> ThreadStatus {
+    let c_str = path_to_c_string(filename.as_ref());
     let result = unsafe {
       ffi::luaL_dofile(self.L, c_str.as_ptr())
     };
     ThreadStatus::from_c_int(result)
   }
 
+  /// Loads and runs the file `filename`, discarding its results. On failure,
+  /// the error message is popped and returned, prefixed with `file error: `,
+  /// `syntax error: ` or `runtime error: ` depending on whether the file could
+  /// not be read, failed to compile or raised an error while running.
+  pub fn run_file<P: AsRef<Path>>(&mut self, filename: P) -> Result<(), String> {
+    let kind = match self.load_file(filename) {
+      ThreadStatus::Ok => match self.pcall(0, 0, 0) {
+        ThreadStatus::Ok => return Ok(()),
+        _ => "runtime error"
+      },
+      ThreadStatus::FileError => "file error",
+      ThreadStatus::SyntaxError => "syntax error",
+      _ => "error"
+    };
+    Err(format!("{}: {}", kind, self.pop_error()))
+  }
+
   /// Maps to `luaL_dostring`.
   pub fn do_string(&mut self, s: &str) -> ThreadStatus {
     let c_str = CString::new(s).unwrap();
@@ -469,6 +964,18 @@ impl State {
     FromLua::from_lua(self, index)
   }
 
+  /// Pushes the given values onto the stack and returns the number of values
+  /// pushed.
+  pub fn push_multi<T: ToLuaMulti>(&mut self, values: T) -> c_int {
+    values.push_multi(self)
+  }
+
+  /// Converts the values starting at `index` to a value of type `T`, usually a
+  /// tuple, and returns it.
+  pub fn to_type_multi<T: FromLuaMulti>(&mut self, index: Index) -> Option<T> {
+    FromLuaMulti::from_lua_multi(self, index)
+  }
+
   //===========================================================================
   // State manipulation
   //===========================================================================
@@ -492,6 +999,20 @@ impl State {
     unsafe { ffi::lua_atpanic(self.L, panicf) }
   }
 
+  /// Installs a panic function that reports errors raised outside of any
+  /// protected call by calling `panic!` with the Lua error message. `State::new`
+  /// installs this function by default; use this function for states created
+  /// through other means.
+  ///
+  /// Lua invokes the panic function from within `lua_error`, so the Rust panic
+  /// cannot unwind back across the C frames in between. The panic message is
+  /// reported through the panic hook, after which the process aborts, just as
+  /// it would without a panic function. Use `pcall` to handle errors
+  /// recoverably.
+  pub fn set_rust_panic_handler(&mut self) -> Function {
+    self.at_panic(Some(panic_func))
+  }
+
   /// Maps to `lua_version`.
   pub fn version(state: Option<&mut State>) -> Number {
     let ptr = match state {
@@ -519,6 +1040,14 @@ impl State {
     unsafe { ffi::lua_settop(self.L, index) }
   }
 
+  /// Returns a guard that restores the stack to its current height when it
+  /// is dropped, unless `StackGuard::commit` is called first. The guard
+  /// dereferences to this state so it can be used in its place.
+  pub fn stack_guard<'a>(&'a mut self) -> StackGuard<'a> {
+    let top = self.get_top();
+    StackGuard { state: self, top: top, armed: true }
+  }
+
   /// Maps to `lua_pushvalue`.
   pub fn push_value(&mut self, index: Index) {
     unsafe { ffi::lua_pushvalue(self.L, index) }
@@ -529,6 +1058,22 @@ impl State {
     unsafe { ffi::lua_rotate(self.L, idx, n) }
   }
 
+  /// Like `rotate`, but first checks that `idx` refers to a value on the
+  /// stack and that `n` is no larger in magnitude than the number of values
+  /// from `idx` to the top, instead of corrupting the stack.
+  pub fn rotate_checked(&mut self, idx: Index, n: c_int) -> Result<(), StackError> {
+    let top = self.get_top();
+    if idx == 0 || (idx as i64).abs() > top as i64 {
+      return Err(StackError::InvalidIndex { index: idx, top: top });
+    }
+    let available = top - self.abs_index(idx) + 1;
+    if (n as i64).abs() > available as i64 {
+      return Err(StackError::CountOutOfRange { count: n, available: available });
+    }
+    self.rotate(idx, n);
+    Ok(())
+  }
+
   /// Maps to `lua_copy`.
   pub fn copy(&mut self, from_idx: Index, to_idx: Index) {
     unsafe { ffi::lua_copy(self.L, from_idx, to_idx) }
@@ -540,6 +1085,25 @@ impl State {
     result != 0
   }
 
+  /// Like `check_stack`, but returns an error instead of `false` when the
+  /// stack cannot grow by `extra` slots, so the failure is not silently
+  /// ignored.
+  pub fn ensure_stack(&mut self, extra: c_int) -> Result<(), StackError> {
+    if self.check_stack(extra) {
+      Ok(())
+    } else {
+      Err(StackError::Overflow { requested: extra })
+    }
+  }
+
+  /// Grows the stack by `extra` slots like `check_stack`, and panics in debug
+  /// builds if that is not possible. Meant for tests and for places where
+  /// running out of stack would be a bug.
+  pub fn assert_stack(&mut self, extra: c_int) {
+    let grown = self.check_stack(extra);
+    debug_assert!(grown, "cannot grow the Lua stack by {} slots", extra);
+  }
+
   /// Maps to `lua_xmove`.
   pub fn xmove(&mut self, to: &mut State, n: c_int) {
     unsafe { ffi::lua_xmove(self.L, to.L, n) }
@@ -610,6 +1174,30 @@ impl State {
     }
   }
 
+  /// Like `to_integerx`, but reports why the conversion failed. Floats are
+  /// converted only if they have an exact integer value, following the rules
+  /// of `lua_numbertointeger`; strings are first converted to numbers.
+  pub fn to_integer_checked(&mut self, index: Index) -> Result<Integer, IntError> {
+    match self.to_integerx(index) {
+      Some(i) => Ok(i),
+      None if self.is_number(index) => Err(IntError::NoIntegerRepresentation),
+      None => Err(IntError::NotANumber)
+    }
+  }
+
+  /// Returns whether the value at `index` is an integer or a float, or `None`
+  /// if it is not a number. Unlike `is_number`, strings convertible to
+  /// numbers are not considered numbers.
+  pub fn number_kind(&mut self, index: Index) -> Option<NumberKind> {
+    if self.type_of(index) != Some(Type::Number) {
+      None
+    } else if self.is_integer(index) {
+      Some(NumberKind::Integer)
+    } else {
+      Some(NumberKind::Float)
+    }
+  }
+
   /// Maps to `lua_toboolean`.
   pub fn to_bool(&mut self, index: Index) -> bool {
     let result = unsafe { ffi::lua_toboolean(self.L, index) };
@@ -618,7 +1206,9 @@ impl State {
 
   // omitted: lua_tolstring
 
-  /// Maps to `lua_rawlen`.
+  /// Maps to `lua_rawlen`. Returns the length of a string or userdata, or the
+  /// border of a table, without invoking the `__len` metamethod. Other values
+  /// have a raw length of 0. See `length` to respect `__len`.
   pub fn raw_len(&mut self, index: Index) -> size_t {
     unsafe { ffi::lua_rawlen(self.L, index) }
   }
@@ -650,6 +1240,25 @@ impl State {
     }
   }
 
+  /// Like `to_thread`, but the returned handle borrows this state, so it
+  /// cannot be used after the parent is dropped or while the parent is used
+  /// to modify its stack (for example to pop the thread, which could let it
+  /// be collected). Returns `None` if the value at `index` is not a thread.
+  ///
+  /// ```compile_fail
+  /// let mut state = lua::State::new();
+  /// state.new_thread();
+  /// let mut thread = state.thread_at(-1).unwrap();
+  /// state.pop(1);
+  /// thread.push_nil();
+  /// ```
+  pub fn thread_at<'a>(&'a mut self, index: Index) -> Option<Thread<'a>> {
+    self.to_thread(index).map(|thread| Thread {
+      thread: thread,
+      _parent: PhantomData,
+    })
+  }
+
   /// Maps to `lua_topointer`.
   pub fn to_pointer(&mut self, index: Index) -> *const c_void {
     unsafe { ffi::lua_topointer(self.L, index) }
@@ -663,6 +1272,19 @@ impl State {
     unsafe { ffi::lua_arith(self.L, op as c_int) }
   }
 
+  /// Checked variant of `arith` that verifies the stack holds enough operands
+  /// for `op` before calling `lua_arith`. Returns `StackError::Underflow` and
+  /// leaves the stack untouched otherwise.
+  pub fn arith_checked(&mut self, op: Arithmetic) -> Result<(), StackError> {
+    let required = op.operands();
+    let available = self.get_top();
+    if available < required {
+      return Err(StackError::Underflow { required: required, available: available });
+    }
+    self.arith(op);
+    Ok(())
+  }
+
   /// Maps to `lua_rawequal`.
   pub fn raw_equal(&mut self, idx1: Index, idx2: Index) -> bool {
     let result = unsafe { ffi::lua_rawequal(self.L, idx1, idx2) };
@@ -675,6 +1297,80 @@ impl State {
     result != 0
   }
 
+  /// Returns `true` if the values at `idx1` and `idx2` are equal, following
+  /// the semantics of `==` including the `__eq` metamethod. Shorthand for
+  /// `compare` with `Comparison::Eq`.
+  pub fn equals(&mut self, idx1: Index, idx2: Index) -> bool {
+    self.compare(idx1, idx2, Comparison::Eq)
+  }
+
+  /// Returns `true` if the value at `idx1` is less than the value at `idx2`,
+  /// following the semantics of `<` including the `__lt` metamethod.
+  pub fn less_than(&mut self, idx1: Index, idx2: Index) -> bool {
+    self.compare(idx1, idx2, Comparison::Lt)
+  }
+
+  /// Returns `true` if the value at `idx1` is less than or equal to the value
+  /// at `idx2`, following the semantics of `<=` including the `__le`
+  /// metamethod.
+  pub fn less_equal(&mut self, idx1: Index, idx2: Index) -> bool {
+    self.compare(idx1, idx2, Comparison::Le)
+  }
+
+  /// Returns `true` if the values at `i1` and `i2` are structurally equal.
+  /// Tables are equal if they have the same keys and their values are deeply
+  /// equal; keys themselves are matched with raw lookups, so table keys must
+  /// be the same table. All other values are compared with `raw_equal`, and
+  /// metatables and metamethods are ignored.
+  ///
+  /// Nested tables are compared recursively, using a few stack slots and a
+  /// Rust stack frame per level; `false` is returned if the Lua stack cannot
+  /// grow any further. A pair of tables that is reached again while it is
+  /// already being compared is assumed to be equal, so cyclic tables with the
+  /// same shape compare equal instead of recursing forever.
+  pub fn deep_equal(&mut self, i1: Index, i2: Index) -> bool {
+    let i1 = self.abs_index(i1);
+    let i2 = self.abs_index(i2);
+    self.deep_equal_visited(i1, i2, &mut HashSet::new())
+  }
+
+  fn deep_equal_visited(&mut self, i1: Index, i2: Index, visited: &mut HashSet<(usize, usize)>) -> bool {
+    if self.raw_equal(i1, i2) {
+      return true;
+    }
+    if !self.is_table(i1) || !self.is_table(i2) {
+      return false;
+    }
+    let pair = (self.to_pointer(i1) as usize, self.to_pointer(i2) as usize);
+    if visited.contains(&pair) {
+      return true;
+    }
+    if !self.check_stack(3) {
+      return false;
+    }
+    visited.insert(pair);
+    let mut count = 0;
+    self.push_nil();
+    while self.next(i1) {
+      count += 1;
+      // stack: key, value1; look up key in the second table
+      self.push_value(-2);
+      self.raw_get(i2);
+      let top = self.get_top();
+      if !self.deep_equal_visited(top - 1, top, visited) {
+        self.pop(3);
+        return false;
+      }
+      self.pop(2);
+    }
+    self.push_nil();
+    while self.next(i2) {
+      count -= 1;
+      self.pop(1);
+    }
+    count == 0
+  }
+
   //===========================================================================
   // Push functions (C -> stack)
   //===========================================================================
@@ -688,6 +1384,23 @@ impl State {
     unsafe { ffi::lua_pushnumber(self.L, n) }
   }
 
+  /// Pushes `n` as an integer if it is a whole number that fits in an
+  /// `Integer`, and as a float otherwise. Useful when values from a format
+  /// without an integer type should round-trip as integers.
+  ///
+  /// Note that every float above 2^53 in magnitude is a whole number, so
+  /// large floats that were never meant as integers are converted too, and
+  /// that `-0.0` becomes the integer `0`, losing its sign.
+  pub fn push_number_smart(&mut self, n: Number) {
+    // 2^63 is exactly representable, unlike `Integer::max_value() as Number`
+    const LIMIT: Number = 9223372036854775808.0;
+    if n.fract() == 0.0 && n >= -LIMIT && n < LIMIT {
+      self.push_integer(n as Integer)
+    } else {
+      self.push_number(n)
+    }
+  }
+
   /// Maps to `lua_pushinteger`.
   pub fn push_integer(&mut self, i: Integer) {
     unsafe { ffi::lua_pushinteger(self.L, i) }
@@ -695,11 +1408,27 @@ impl State {
 
   // omitted: lua_pushstring
 
-  /// Maps to `lua_pushlstring`.
+  /// Maps to `lua_pushlstring`. The string may contain nul bytes.
   pub fn push_string(&mut self, s: &str) {
     unsafe { ffi::lua_pushlstring(self.L, s.as_ptr() as *const _, s.len() as size_t) };
   }
 
+  /// Stores the string `s` in the registry and returns a reference to it
+  /// for `push_interned`. The string stays alive until the reference is
+  /// released with `unreference(REGISTRYINDEX, reference)`.
+  pub fn intern(&mut self, s: &str) -> Reference {
+    self.push_string(s);
+    self.reference(REGISTRYINDEX)
+  }
+
+  /// Pushes the string stored by `intern` under `key`. This is a single array
+  /// lookup in the registry, so unlike `push_string` the string is neither
+  /// hashed nor copied again, which pays off for field names pushed over and
+  /// over (see the `interned-bench` example).
+  pub fn push_interned(&mut self, key: Reference) {
+    self.raw_geti(REGISTRYINDEX, key.value() as Integer);
+  }
+
   /// Maps to `lua_pushlstring`.
   pub fn push_bytes(&mut self, s: &[u8]) {
     unsafe { ffi::lua_pushlstring(self.L, s.as_ptr() as *const _, s.len() as size_t) };
@@ -708,11 +1437,94 @@ impl State {
   // omitted: lua_pushvfstring
   // omitted: lua_pushfstring
 
+  /// Formats `args` and pushes the result as a string. This takes the place
+  /// of `lua_pushfstring`; see also the `lua_format!` macro.
+  pub fn push_fmt(&mut self, args: fmt::Arguments) {
+    let s = fmt::format(args);
+    self.push_bytes(s.as_bytes())
+  }
+
   /// Maps to `lua_pushcclosure`.
   pub fn push_closure(&mut self, f: Function, n: c_int) {
     unsafe { ffi::lua_pushcclosure(self.L, f, n) }
   }
 
+  /// Pushes a Rust closure onto the stack as a native closure. Unlike
+  /// `lua_func!`, the closure may capture state. It is boxed and stored as a
+  /// full userdata upvalue whose `__gc` metamethod drops it once the Lua
+  /// closure is collected. Since the closure lives inside the state, which
+  /// may be sent to another thread, it must be `Send`:
+  ///
+  /// ```compile_fail
+  /// let mut state = lua::State::new();
+  /// let shared = std::rc::Rc::new(std::cell::Cell::new(0));
+  /// state.push_closure_boxed(move |_| { shared.set(1); 0 });
+  /// ```
+  ///
+  /// # Safety requirement
+  ///
+  /// The closure must not cause itself to be called again while it is
+  /// running, for example by calling a Lua function that calls it back, or
+  /// by resuming a coroutine that does. The nested call would create a second
+  /// mutable reference to the closure, which is undefined behavior. Calling
+  /// it again after it has returned, or after it raised an error, is fine.
+  pub fn push_closure_boxed<F>(&mut self, f: F)
+    where F: FnMut(&mut State) -> c_int + Send + 'static
+  {
+    #[allow(non_snake_case)]
+    unsafe extern "C" fn call_boxed<F>(L: *mut lua_State) -> c_int
+      where F: FnMut(&mut State) -> c_int
+    {
+      let mut state = State::from_ptr(L);
+      let f = state.to_userdata(ffi::lua_upvalueindex(1)) as *mut Box<F>;
+      (**f)(&mut state)
+    }
+    self.push_boxed(f);
+    self.push_closure(Some(call_boxed::<F>), 1);
+  }
+
+  /// Pushes a native closure that advances `iter` each time it is called,
+  /// returning the next item or `nil` once the iterator is exhausted. This
+  /// makes Rust iterators usable in a generic `for` loop:
+  ///
+  /// ```ignore
+  /// state.push_iterator(0..3);
+  /// state.set_global("numbers");
+  /// state.do_string("for n in numbers do print(n) end");
+  /// ```
+  ///
+  /// The iterator is stored like a `push_closure_boxed` closure and dropped
+  /// when the function is collected, so it must be `Send` for the same
+  /// reason. An item that converts to `nil` ends a `for` loop early.
+  pub fn push_iterator<I, T>(&mut self, iter: I)
+    where I: Iterator<Item = T> + Send + 'static, T: ToLua
+  {
+    let mut iter = iter.fuse();
+    self.push_closure_boxed(move |state| {
+      match iter.next() {
+        Some(value) => value.to_lua(state),
+        None => state.push_nil(),
+      }
+      1
+    });
+  }
+
+  // Pushes a full userdata holding `Box<T>` whose `__gc` metamethod drops it.
+  fn push_boxed<T: 'static>(&mut self, value: T) {
+    #[allow(non_snake_case)]
+    unsafe extern "C" fn drop_boxed<T>(L: *mut lua_State) -> c_int {
+      let mut state = State::from_ptr(L);
+      let value = state.to_userdata(1) as *mut Box<T>;
+      ptr::drop_in_place(value);
+      0
+    }
+    unsafe { ptr::write(self.new_userdata_typed::<Box<T>>(), Box::new(value)) };
+    self.create_table(0, 1);
+    self.push_fn(Some(drop_boxed::<T>));
+    self.set_field(-2, "__gc");
+    self.set_metatable(-2);
+  }
+
   /// Maps to `lua_pushboolean`.
   pub fn push_bool(&mut self, b: bool) {
     unsafe { ffi::lua_pushboolean(self.L, b as c_int) }
@@ -735,31 +1547,94 @@ impl State {
   //===========================================================================
   // Get functions (Lua -> stack)
   //===========================================================================
-  /// Maps to `lua_getglobal`.
+  /// Maps to `lua_getglobal`. Names containing nul bytes are supported, but
+  /// need an extra stack slot.
   pub fn get_global(&mut self, name: &str) -> Type {
-    let c_str = CString::new(name).unwrap();
+    let c_str = match CString::new(name) {
+      Ok(c_str) => c_str,
+      Err(_) => {
+        self.push_global_table();
+        let ty = self.get_field(-1, name);
+        self.remove(-2);
+        return ty;
+      }
+    };
     let ty = unsafe {
       ffi::lua_getglobal(self.L, c_str.as_ptr())
     };
     Type::from_c_int(ty).unwrap()
   }
 
+  /// Convenience function that calls `get_global` and returns the type of the
+  /// pushed value. If the global is `nil`, it is popped and `None` is returned
+  /// instead, leaving the stack unchanged.
+  pub fn get_global_opt(&mut self, name: &str) -> Option<Type> {
+    match self.get_global(name) {
+      Type::Nil => {
+        self.pop(1);
+        None
+      }
+      ty => Some(ty)
+    }
+  }
+
   /// Maps to `lua_gettable`.
   pub fn get_table(&mut self, index: Index) -> Type {
     let ty = unsafe { ffi::lua_gettable(self.L, index) };
     Type::from_c_int(ty).unwrap()
   }
 
-  /// Maps to `lua_getfield`.
+  /// Maps to `lua_getfield`. Keys containing nul bytes are supported, but
+  /// need an extra stack slot.
   pub fn get_field(&mut self, index: Index, k: &str) -> Type {
-    let c_str = CString::new(k).unwrap();
+    let c_str = match CString::new(k) {
+      Ok(c_str) => c_str,
+      Err(_) => return self.get_field_bytes(index, k.as_bytes())
+    };
     let ty = unsafe {
       ffi::lua_getfield(self.L, index, c_str.as_ptr())
     };
     Type::from_c_int(ty).unwrap()
   }
 
-  /// Maps to `lua_geti`.
+  /// Like `get_field`, but the key may be an arbitrary byte string. Pushes
+  /// `t[k]`, where `t` is the value at `index`, and returns its type. This may
+  /// trigger the `__index` metamethod. `get_field` is cheaper for keys that
+  /// are valid UTF-8 without nul bytes.
+  pub fn get_field_bytes(&mut self, index: Index, k: &[u8]) -> Type {
+    let index = self.abs_index(index);
+    self.push_bytes(k);
+    self.get_table(index)
+  }
+
+  /// Reads `t[k]`, where `t` is the value at `index`, and converts it with
+  /// `FromLua`, leaving the stack unchanged. Returns `None` if the field is
+  /// `nil` (even for types such as `Option<T>` that accept `nil`) or has the
+  /// wrong type. This may trigger the `__index` metamethod.
+  pub fn field<T: FromLua>(&mut self, index: Index, k: &str) -> Option<T> {
+    let value = match self.get_field(index, k) {
+      Type::Nil => None,
+      _ => self.to_type(-1),
+    };
+    self.pop(1);
+    value
+  }
+
+  /// Convenience function that calls `get_field` and returns the type of the
+  /// pushed value. If the field is `nil`, it is popped and `None` is returned
+  /// instead, leaving the stack unchanged.
+  pub fn get_field_opt(&mut self, index: Index, k: &str) -> Option<Type> {
+    match self.get_field(index, k) {
+      Type::Nil => {
+        self.pop(1);
+        None
+      }
+      ty => Some(ty)
+    }
+  }
+
+  /// Maps to `lua_geti`. This may trigger the `__index` metamethod; use
+  /// `raw_geti` to bypass it.
   pub fn geti(&mut self, index: Index, i: Integer) -> Type {
     let ty = unsafe {
       ffi::lua_geti(self.L, index, i)
@@ -767,13 +1642,29 @@ impl State {
     Type::from_c_int(ty).unwrap()
   }
 
+  /// Like `geti`, but takes a 0-based Rust index: `get_array(t, 0)` pushes
+  /// `t[1]`, the first element of a Lua sequence. Returns the type of the
+  /// pushed value.
+  pub fn get_array(&mut self, index: Index, rust_idx: usize) -> Type {
+    self.geti(index, rust_idx as Integer + 1)
+  }
+
   /// Maps to `lua_rawget`.
   pub fn raw_get(&mut self, index: Index) -> Type {
     let ty = unsafe { ffi::lua_rawget(self.L, index) };
     Type::from_c_int(ty).unwrap()
   }
 
-  /// Maps to `lua_rawgeti`.
+  /// Like `get_field`, but does a raw access through `raw_get` that does not
+  /// trigger the `__index` metamethod.
+  pub fn get_field_raw(&mut self, index: Index, k: &str) -> Type {
+    let index = self.abs_index(index);
+    self.push_string(k);
+    self.raw_get(index)
+  }
+
+  /// Maps to `lua_rawgeti`. Unlike `geti`, this does not trigger the `__index`
+  /// metamethod.
   pub fn raw_geti(&mut self, index: Index, n: Integer) -> Type {
     let ty = unsafe { ffi::lua_rawgeti(self.L, index, n) };
     Type::from_c_int(ty).unwrap()
@@ -790,6 +1681,45 @@ impl State {
     unsafe { ffi::lua_createtable(self.L, narr, nrec) }
   }
 
+  /// Pushes a new table holding the key-value pairs of `entries`, sized
+  /// from the iterator's lower size hint. Later entries overwrite earlier
+  /// ones with the same key. Entries whose key is `nil` or NaN, which Lua
+  /// cannot store, are skipped, as are entries whose value is `nil`.
+  pub fn push_map<I, K, V>(&mut self, entries: I)
+    where I: IntoIterator<Item = (K, V)>, K: ToLua, V: ToLua
+  {
+    let entries = entries.into_iter();
+    let size = cmp::min(entries.size_hint().0, c_int::max_value() as usize);
+    self.create_table(0, size as c_int);
+    let table = self.get_top();
+    for (k, v) in entries {
+      k.to_lua(self);
+      let key = self.get_top();
+      if self.is_nil(key) || (self.is_number(key) && self.to_number(key).is_nan()) {
+        self.pop(1);
+        continue;
+      }
+      v.to_lua(self);
+      self.raw_set(table);
+    }
+  }
+
+  /// Pushes a new empty table whose metatable sets `__mode` according to
+  /// `mode`, so that entries whose weak keys or values are otherwise
+  /// unreferenced are removed by the garbage collector. Each call creates a
+  /// separate metatable.
+  pub fn create_weak_table(&mut self, mode: WeakMode) {
+    self.new_table();
+    self.create_table(0, 1);
+    self.push_string(match mode {
+      WeakMode::Keys => "k",
+      WeakMode::Values => "v",
+      WeakMode::Both => "kv",
+    });
+    self.set_field(-2, "__mode");
+    self.set_metatable(-2);
+  }
+
   /// Maps to `lua_newuserdata`. The pointer returned is owned by the Lua state
   /// and it will be garbage collected when it is no longer in use or the state
   /// is closed. To specify custom cleanup behavior, use a `__gc` metamethod.
@@ -811,12 +1741,133 @@ impl State {
     self.new_userdata(mem::size_of::<T>() as size_t) as *mut T
   }
 
-  /// Maps to `lua_getmetatable`.
-  pub fn get_metatable(&mut self, objindex: Index) -> bool {
-    let result = unsafe { ffi::lua_getmetatable(self.L, objindex) };
-    result != 0
-  }
-
+  /// Convenience function that allocates a new userdata, moves `value` into
+  /// it, and sets its metatable to the registry metatable named `metatable`.
+  /// The userdata is left on top of the stack and a pointer to it is
+  /// returned.
+  ///
+  /// The value is not dropped automatically; the metatable should provide a
+  /// `__gc` metamethod if `T` needs to be dropped, as one created with
+  /// `register_type::<T>` does.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `metatable` was created by `register_type` for a type other
+  /// than `T`, since its `__gc` would drop the value as the wrong type.
+  ///
+  /// # Example
+  ///
+  /// ```ignore
+  /// state.push_userdata(MyStruct::new(...), "MyStruct");
+  /// ```
+  pub fn push_userdata<T: 'static>(&mut self, value: T, metatable: &str) -> *mut T {
+    if let Some(tag) = self.registered_type_tag(metatable) {
+      if tag != any::TypeId::of::<T>() {
+        panic!("push_userdata: metatable '{}' was registered for a different type", metatable);
+      }
+    }
+    let ptr = self.new_userdata_typed::<T>();
+    unsafe { ptr::write(ptr, value) };
+    self.set_metatable_from_registry(metatable);
+    // Copy the metatable's tag to the value itself, so that attaching the
+    // metatable to some other userdata later does not make it pass as a `T`.
+    if self.get_metatable(-1) {
+      self.raw_getp(-1, &TYPE_TAG_KEY as *const u8);
+      self.set_uservalue(-3);
+      self.pop(1);
+    }
+    ptr
+  }
+
+  /// Creates the registry metatable `name` for userdata holding a `T`, with a
+  /// `__gc` metamethod that drops the value. Userdata pushed with
+  /// `push_userdata::<T>(value, name)` is then dropped when it is collected.
+  /// The metatable's `__index` refers to itself, so methods can be added
+  /// later with `metatable(name)`. If the metatable already exists its `__gc`
+  /// is replaced.
+  ///
+  /// The metatable is also tagged with the `TypeId` of `T`. `push_userdata`
+  /// checks the tag and copies it to the user value of the new userdata, and
+  /// both `__gc` and `get_userdata` only treat userdata carrying the tag as a
+  /// `T`.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `name` was already registered for a type other than `T`.
+  pub fn register_type<T: 'static>(&mut self, name: &str) {
+    if let Some(tag) = self.registered_type_tag(name) {
+      if tag != any::TypeId::of::<T>() {
+        panic!("register_type: metatable '{}' is already registered for a different type", name);
+      }
+    }
+    #[allow(non_snake_case)]
+    unsafe extern "C" fn drop_userdata<T: 'static>(L: *mut lua_State) -> c_int {
+      let mut state = State::from_ptr(L);
+      if state.has_type_tag::<T>(1) {
+        ptr::drop_in_place(state.to_userdata(1) as *mut T);
+      }
+      0
+    }
+    self.metatable(name)
+      .meta("__gc", Some(drop_userdata::<T>))
+      .finish();
+    self.get_metatable_from_registry(name);
+    unsafe { ptr::write(self.new_userdata_typed::<any::TypeId>(), any::TypeId::of::<T>()) };
+    self.raw_setp(-2, &TYPE_TAG_KEY as *const u8);
+    self.pop(1);
+  }
+
+  // Returns the `TypeId` that `register_type` stored in the registry
+  // metatable `name`, if any.
+  fn registered_type_tag(&mut self, name: &str) -> Option<any::TypeId> {
+    self.get_metatable_from_registry(name);
+    if !self.is_table(-1) {
+      self.pop(1);
+      return None;
+    }
+    self.raw_getp(-1, &TYPE_TAG_KEY as *const u8);
+    let tag = self.to_userdata(-1) as *const any::TypeId;
+    let result = if tag.is_null() { None } else { Some(unsafe { *tag }) };
+    self.pop(2);
+    result
+  }
+
+  // Returns whether the value at `index` is a full userdata whose user value
+  // is the tag of `T` copied there by `push_userdata`.
+  fn has_type_tag<T: 'static>(&mut self, index: Index) -> bool {
+    if self.type_of(index) != Some(Type::Userdata) {
+      return false;
+    }
+    self.get_uservalue(index);
+    let tag = self.to_userdata(-1) as *const any::TypeId;
+    let matches = !tag.is_null() && unsafe { *tag } == any::TypeId::of::<T>();
+    self.pop(1);
+    matches
+  }
+
+  /// Returns a reference to the `T` stored in the userdata at `index`, or
+  /// `None` if the value is not a full userdata pushed with
+  /// `push_userdata::<T>` under a metatable created by `register_type::<T>`.
+  /// Unlike `to_userdata_typed` and `check_userdata_typed`, this cannot
+  /// confuse one registered type with another.
+  ///
+  /// The tag is kept in the user value of the userdata, so Lua code with
+  /// access to the `debug` library could still defeat the check with
+  /// `debug.setuservalue`.
+  pub fn get_userdata<'a, T: 'static>(&'a mut self, index: Index) -> Option<&'a mut T> {
+    if self.has_type_tag::<T>(index) {
+      unsafe { (self.to_userdata(index) as *mut T).as_mut() }
+    } else {
+      None
+    }
+  }
+
+  /// Maps to `lua_getmetatable`.
+  pub fn get_metatable(&mut self, objindex: Index) -> bool {
+    let result = unsafe { ffi::lua_getmetatable(self.L, objindex) };
+    result != 0
+  }
+
   /// Maps to `lua_getuservalue`.
   pub fn get_uservalue(&mut self, idx: Index) -> Type {
     let result = unsafe { ffi::lua_getuservalue(self.L, idx) };
@@ -825,11 +1876,23 @@ impl State {
 
   //===========================================================================
   // Set functions (stack -> Lua)
+  //
+  // Helpers that push values of their own before assigning must convert the
+  // target index with `abs_index` first, so that relative indices refer to
+  // the stack as the caller saw it.
   //===========================================================================
-  /// Maps to `lua_setglobal`.
+  /// Maps to `lua_setglobal`. Names containing nul bytes are supported, but
+  /// need two extra stack slots.
   pub fn set_global(&mut self, var: &str) {
-    let c_str = CString::new(var).unwrap();
-    unsafe { ffi::lua_setglobal(self.L, c_str.as_ptr()) }
+    match CString::new(var) {
+      Ok(c_str) => unsafe { ffi::lua_setglobal(self.L, c_str.as_ptr()) },
+      Err(_) => {
+        self.push_global_table();
+        self.insert(-2);
+        self.set_field(-2, var);
+        self.pop(1);
+      }
+    }
   }
 
   /// Maps to `lua_settable`.
@@ -837,23 +1900,71 @@ impl State {
     unsafe { ffi::lua_settable(self.L, idx) }
   }
 
-  /// Maps to `lua_setfield`.
+  /// Maps to `lua_setfield`. Keys containing nul bytes are supported, but
+  /// need an extra stack slot.
   pub fn set_field(&mut self, idx: Index, k: &str) {
-    let c_str = CString::new(k).unwrap();
-    unsafe { ffi::lua_setfield(self.L, idx, c_str.as_ptr()) }
+    match CString::new(k) {
+      Ok(c_str) => unsafe { ffi::lua_setfield(self.L, idx, c_str.as_ptr()) },
+      Err(_) => self.set_field_bytes(idx, k.as_bytes())
+    }
+  }
+
+  /// Assigns `value` to `t[k]`, where `t` is the value at `index`. `index`
+  /// is made absolute before the value is pushed, so relative indices refer
+  /// to the stack as it was on entry. This may trigger the `__newindex`
+  /// metamethod.
+  pub fn set_field_value<T: ToLua>(&mut self, index: Index, k: &str, value: T) {
+    let index = self.abs_index(index);
+    value.to_lua(self);
+    self.set_field(index, k);
+  }
+
+  /// Like `set_field`, but takes a NUL-terminated key so that no `CString`
+  /// has to be built. Use `lua_cstr!` to create the key from a literal.
+  pub fn set_field_static(&mut self, idx: Index, k: &'static CStr) {
+    unsafe { ffi::lua_setfield(self.L, idx, k.as_ptr()) }
   }
 
-  /// Maps to `lua_seti`.
+  /// Like `set_field`, but the key may be an arbitrary byte string. Does the
+  /// equivalent of `t[k] = v`, where `t` is the value at `idx` and `v` is the
+  /// value on the top of the stack, and pops the value. This may trigger the
+  /// `__newindex` metamethod.
+  pub fn set_field_bytes(&mut self, idx: Index, k: &[u8]) {
+    let idx = self.abs_index(idx);
+    self.push_bytes(k);
+    self.insert(-2);
+    self.set_table(idx);
+  }
+
+  /// Maps to `lua_seti`. This may trigger the `__newindex` metamethod; use
+  /// `raw_seti` to bypass it.
   pub fn seti(&mut self, idx: Index, n: Integer) {
     unsafe { ffi::lua_seti(self.L, idx, n) }
   }
 
+  /// Like `seti`, but takes a 0-based Rust index: `set_array(t, 0)` pops the
+  /// value on the top of the stack and assigns it to `t[1]`, the first
+  /// element of a Lua sequence.
+  pub fn set_array(&mut self, idx: Index, rust_idx: usize) {
+    self.seti(idx, rust_idx as Integer + 1)
+  }
+
   /// Maps to `lua_rawset`.
   pub fn raw_set(&mut self, idx: Index) {
     unsafe { ffi::lua_rawset(self.L, idx) }
   }
 
-  /// Maps to `lua_rawseti`.
+  /// Like `set_field`, but does a raw assignment through `raw_set` that does
+  /// not trigger the `__newindex` metamethod. Pops the value from the stack.
+  pub fn set_field_raw(&mut self, idx: Index, k: &str) {
+    let idx = self.abs_index(idx);
+    self.push_string(k);
+    self.insert(-2);
+    self.raw_set(idx)
+  }
+
+  /// Maps to `lua_rawseti`. Unlike `seti`, this does not trigger the
+  /// `__newindex` metamethod.
   pub fn raw_seti(&mut self, idx: Index, n: Integer) {
     unsafe { ffi::lua_rawseti(self.L, idx, n) }
   }
@@ -914,8 +2025,161 @@ impl State {
     ThreadStatus::from_c_int(result)
   }
 
+  /// Calls the function below the top `nargs` values in protected mode with a
+  /// message handler that appends a traceback to the error message. The
+  /// handler is removed from the stack afterwards, so on success the stack
+  /// looks as it would after `pcall`. On failure, the error message including
+  /// the traceback is popped and returned.
+  pub fn pcall_traceback(&mut self, nargs: c_int, nresults: c_int) -> Result<(), String> {
+    let base = self.get_top() - nargs;
+    self.push_fn(Some(traceback_handler));
+    self.insert(base);
+    let status = self.pcall(nargs, nresults, base);
+    let result = if status.is_err() {
+      Err(self.pop_error())
+    } else {
+      Ok(())
+    };
+    self.remove(base);
+    result
+  }
+
+  /// Calls a function in protected mode like `pcall`, requesting as many
+  /// results as `R` reads and converting them. The results are popped off the
+  /// stack. Missing results are filled with `nil` by Lua, so `Option` may be
+  /// used for results that are not always present.
+  ///
+  /// On failure, or if the results cannot be converted to `R`, an error
+  /// message is returned instead.
+  pub fn pcall_returns<R: FromLuaMulti>(&mut self, nargs: c_int, msgh: c_int) -> Result<R, String> {
+    if self.pcall(nargs, R::COUNT, msgh).is_err() {
+      return Err(self.pop_error());
+    }
+    let result = R::from_lua_multi(self, -R::COUNT);
+    self.pop(R::COUNT);
+    result.ok_or_else(|| "could not convert the returned values".to_owned())
+  }
+
+  /// Converts the error object on the top of the stack with `FromLua` and
+  /// pops it. Unlike the helpers that return a `String`, this can recover
+  /// errors raised with a non-string value, such as `error({code = 5})`.
+  /// Returns `None` if the conversion fails; the value is popped either way.
+  pub fn take_error_value<T: FromLua>(&mut self) -> Option<T> {
+    let value = self.to_type(-1);
+    self.pop(1);
+    value
+  }
+
+  /// Calls a function in protected mode like `pcall`. On failure, the error
+  /// object is popped and converted with `take_error_value`, so the error is
+  /// `None` if it could not be converted to `E`.
+  pub fn pcall_error_value<E: FromLua>(&mut self, nargs: c_int, nresults: c_int, msgh: c_int) -> Result<(), Option<E>> {
+    if self.pcall(nargs, nresults, msgh).is_err() {
+      Err(self.take_error_value())
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Calls the global function `name` in protected mode with the values in
+  /// `args` as arguments, leaving `nresults` results on the stack. On failure,
+  /// the error message is popped and returned instead.
+  ///
+  /// # Example
+  ///
+  /// ```ignore
+  /// state.call_global("print", (1, "two", 3.0), 0)?;
+  /// ```
+  pub fn call_global<A: ToLuaMulti>(&mut self, name: &str, args: A, nresults: c_int) -> Result<(), String> {
+    self.get_global(name);
+    let nargs = args.push_multi(self);
+    if self.pcall(nargs, nresults, 0).is_err() {
+      Err(self.pop_error())
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Calls the function at `fn_index` in protected mode with the values in
+  /// `args` as arguments and converts its results like `pcall_returns`. The
+  /// function is copied to the top with `push_value` first, so it stays at
+  /// `fn_index` and can be called again.
+  pub fn pcall_index<A: ToLuaMulti, R: FromLuaMulti>(&mut self, fn_index: Index, args: A) -> Result<R, String> {
+    self.push_value(fn_index);
+    let nargs = args.push_multi(self);
+    self.pcall_returns(nargs, 0)
+  }
+
+  /// Runs `body` with values that are cleaned up deterministically, even when
+  /// `body` raises a Lua error.
+  ///
+  /// `setup` is called first and may push any number of values (the scoped
+  /// values). `body` is then called in protected mode and sees copies of the
+  /// scoped values at indices `1` to `n`; anything else it pushes is
+  /// discarded. Afterwards `cleanup` is called with the scoped values on the
+  /// top of the stack, and they are removed.
+  ///
+  /// Returns the value returned by `body`. If `body` raised an error, returns
+  /// the status of the protected call instead and leaves the error object on
+  /// the top of the stack, like `pcall`; a native function that wants an
+  /// enclosing `pcall` to observe the error can raise it again with `error`.
+  ///
+  /// `body` must not panic, since the panic would unwind through Lua.
+  pub fn with_scoped_value<S, F, C, R>(&mut self, setup: S, body: F, cleanup: C) -> Result<R, ThreadStatus>
+    where S: FnOnce(&mut State), F: FnOnce(&mut State) -> R, C: FnOnce(&mut State)
+  {
+    #[allow(non_snake_case)]
+    unsafe extern "C" fn run<G: FnMut(&mut State)>(L: *mut lua_State) -> c_int {
+      let mut state = State::from_ptr(L);
+      let g = state.to_userdata(ffi::lua_upvalueindex(1)) as *mut G;
+      (*g)(&mut state);
+      0
+    }
+    // the closure lives on the Rust stack, which outlives the protected call
+    fn push_runner<G: FnMut(&mut State)>(state: &mut State, g: &mut G) {
+      unsafe { state.push_light_userdata(g as *mut G) };
+      state.push_closure(Some(run::<G>), 1);
+    }
+    let base = self.get_top();
+    setup(self);
+    let n = self.get_top() - base;
+
+    let mut body = Some(body);
+    let mut result = None;
+    let status;
+    {
+      let mut run_body = |state: &mut State| {
+        if let Some(body) = body.take() {
+          result = Some(body(state));
+        }
+      };
+      push_runner(self, &mut run_body);
+      for i in 1..n + 1 {
+        self.push_value(base + i);
+      }
+      status = self.pcall(n, 0, 0);
+    }
+
+    if status.is_err() {
+      // keep the error object below the scoped values while cleaning up
+      self.insert(base + 1);
+      cleanup(self);
+      self.set_top(base + 1);
+      return Err(status);
+    }
+    cleanup(self);
+    self.set_top(base);
+    Ok(result.expect("body of with_scoped_value did not run"))
+  }
+
   // TODO: mode typing?
   /// Maps to `lua_load`.
+  ///
+  /// Lua reads the slice returned by `reader` until it calls `reader` again,
+  /// so the slice must stay valid until then; returning a slice of a buffer
+  /// that `reader` overwrites or frees on the next call is fine, but a slice
+  /// of a temporary is not. An empty slice ends the chunk. See `load_chunks`
+  /// and `load_reader` for safer alternatives.
   pub fn load<'l, F>(&'l mut self, mut reader: F, source: &str, mode: &str) -> ThreadStatus
     where F: FnMut(&mut State) -> &'l [u8]
   {
@@ -935,6 +2199,105 @@ impl State {
     ThreadStatus::from_c_int(result)
   }
 
+  /// Compiles a Lua chunk read from `reader` and pushes it onto the stack as a
+  /// function. `chunkname` and `mode` have the same meaning as in `lua_load`.
+  /// The reader is consumed in fixed-size blocks, so it does not need to be
+  /// buffered. On failure, nothing is pushed and the compile error, or the
+  /// message of the `io::Error` that interrupted reading, is returned.
+  pub fn load_reader<R: io::Read>(&mut self, reader: R, chunkname: &str, mode: &str) -> Result<(), String> {
+    struct ReadState<R> {
+      reader: R,
+      buf: [u8; 4096],
+      error: Option<io::Error>,
+    }
+    unsafe extern "C" fn read<R: io::Read>(_: *mut lua_State, ud: *mut c_void, sz: *mut size_t) -> *const c_char {
+      let rs = &mut *(ud as *mut ReadState<R>);
+      loop {
+        match rs.reader.read(&mut rs.buf) {
+          Ok(n) => {
+            *sz = n as size_t;
+            return rs.buf.as_ptr() as *const c_char;
+          }
+          Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+          Err(e) => {
+            rs.error = Some(e);
+            *sz = 0;
+            return ptr::null();
+          }
+        }
+      }
+    }
+    let mut rs = ReadState { reader: reader, buf: [0; 4096], error: None };
+    let chunkname_c_str = CString::new(chunkname).unwrap();
+    let mode_c_str = CString::new(mode).unwrap();
+    let result = unsafe {
+      ffi::lua_load(self.L, Some(read::<R>), &mut rs as *mut ReadState<R> as *mut c_void, chunkname_c_str.as_ptr(), mode_c_str.as_ptr())
+    };
+    // a read error looks like the end of the input to Lua, so the chunk may
+    // still have compiled; discard whatever was pushed in that case
+    if let Some(e) = rs.error {
+      self.pop(1);
+      return Err(format!("cannot read {}: {}", chunkname, e));
+    }
+    match ThreadStatus::from_c_int(result) {
+      ThreadStatus::Ok => Ok(()),
+      _ => Err(self.pop_error())
+    }
+  }
+
+  /// Compiles a Lua chunk made up of the buffers yielded by `chunks` and
+  /// pushes it onto the stack as a function. Each buffer is kept alive until
+  /// Lua asks for the next one, and empty buffers are skipped rather than
+  /// ending the chunk early. On failure, nothing is pushed and the error
+  /// message is returned.
+  pub fn load_chunks<I>(&mut self, chunks: I, chunkname: &str, mode: &str) -> Result<(), String>
+    where I: Iterator<Item = Vec<u8>>
+  {
+    struct ChunkState<I> {
+      chunks: I,
+      current: Vec<u8>,
+    }
+    unsafe extern "C" fn read<I>(_: *mut lua_State, ud: *mut c_void, sz: *mut size_t) -> *const c_char
+      where I: Iterator<Item = Vec<u8>>
+    {
+      let cs = &mut *(ud as *mut ChunkState<I>);
+      cs.current = cs.chunks.by_ref().find(|chunk| !chunk.is_empty()).unwrap_or_default();
+      *sz = cs.current.len() as size_t;
+      cs.current.as_ptr() as *const c_char
+    }
+    let mut cs = ChunkState { chunks: chunks, current: Vec::new() };
+    let chunkname_c_str = CString::new(chunkname).unwrap();
+    let mode_c_str = CString::new(mode).unwrap();
+    let result = unsafe {
+      ffi::lua_load(self.L, Some(read::<I>), &mut cs as *mut ChunkState<I> as *mut c_void, chunkname_c_str.as_ptr(), mode_c_str.as_ptr())
+    };
+    match ThreadStatus::from_c_int(result) {
+      ThreadStatus::Ok => Ok(()),
+      _ => Err(self.pop_error())
+    }
+  }
+
+  /// Compiles `source` as a Lua chunk named `chunkname` and pushes it onto the
+  /// stack as a function. The chunk name appears in error messages and
+  /// tracebacks; see `lua_load` for its conventions. On failure, the error
+  /// message is popped and returned instead.
+  pub fn compile(&mut self, source: &str, chunkname: &str) -> Result<(), String> {
+    match self.load_bufferx(source.as_bytes(), chunkname, "bt") {
+      ThreadStatus::Ok => Ok(()),
+      _ => Err(self.pop_error())
+    }
+  }
+
+  /// Pops the error object on top of the stack and returns it as a message.
+  fn pop_error(&mut self) -> String {
+    let msg = self.to_bytes_in_place(-1).map(|bytes| String::from_utf8_lossy(bytes).into_owned());
+    let msg = msg.unwrap_or_else(|| {
+      format!("(error object is a {} value)", self.typename_at(-1))
+    });
+    self.pop(1);
+    msg
+  }
+
   // returns isize because the return value is dependent on the writer - seems to
   // be usable for anything
   /// Maps to `lua_dump`.
@@ -949,22 +2312,140 @@ impl State {
     unsafe { ffi::lua_dump(self.L, Some(write::<F>), mem::transmute(&mut writer), strip as c_int) }
   }
 
+  /// Dumps the Lua function on the top of the stack as a binary chunk into
+  /// `writer`, using `dump`. The function is not popped. Writing stops at the
+  /// first I/O error, which is returned; dumping a function that is not a Lua
+  /// function fails with `ErrorKind::InvalidInput`.
+  pub fn dump_writer<W: io::Write>(&mut self, mut writer: W, strip: bool) -> io::Result<()> {
+    let mut error = None;
+    let result = self.dump(|_, bytes| {
+      match writer.write_all(bytes) {
+        Ok(()) => 0,
+        Err(e) => {
+          error = Some(e);
+          1
+        }
+      }
+    }, strip);
+    match error {
+      Some(e) => Err(e),
+      None if result != 0 => Err(io::Error::new(io::ErrorKind::InvalidInput, "not a Lua function")),
+      None => Ok(())
+    }
+  }
+
+  /// Serializes `args` according to `fmt` with Lua's `string.pack`, returning
+  /// the packed bytes or the error message raised by `string.pack`. Byte order
+  /// is native unless selected in `fmt` with `<`, `>` or `=`.
+  ///
+  /// The string library does not need to be visible to Lua code; if it has
+  /// not been loaded yet it is loaded into the registry's `_LOADED` table
+  /// without setting a global, which also installs the string metatable.
+  pub fn pack(&mut self, fmt: &str, args: &[PackValue]) -> Result<Vec<u8>, String> {
+    self.push_string_lib_fn("pack");
+    self.push_string(fmt);
+    for arg in args {
+      arg.to_lua(self);
+    }
+    match self.pcall(args.len() as c_int + 1, 1, 0) {
+      ThreadStatus::Ok => {
+        let bytes = self.to_bytes_in_place(-1).map(ToOwned::to_owned).unwrap_or_default();
+        self.pop(1);
+        Ok(bytes)
+      }
+      _ => Err(self.pop_error())
+    }
+  }
+
+  /// Deserializes `data` according to `fmt` with Lua's `string.unpack`. The
+  /// final position returned by `string.unpack` is not included in the
+  /// result. See `pack` for how the string library is accessed.
+  pub fn unpack(&mut self, fmt: &str, data: &[u8]) -> Result<Vec<PackValue>, String> {
+    let top = self.get_top();
+    self.push_string_lib_fn("unpack");
+    self.push_string(fmt);
+    self.push_bytes(data);
+    if self.pcall(2, MULTRET, 0).is_err() {
+      return Err(self.pop_error());
+    }
+    let mut values = Vec::new();
+    // skip the position of the first unread byte
+    for index in top + 1..self.get_top() {
+      values.push(if self.is_integer(index) {
+        PackValue::Integer(self.to_integer(index))
+      } else if self.type_of(index) == Some(Type::Number) {
+        PackValue::Number(self.to_number(index))
+      } else {
+        PackValue::Bytes(self.to_bytes_in_place(index).map(ToOwned::to_owned).unwrap_or_default())
+      });
+    }
+    self.set_top(top);
+    Ok(values)
+  }
+
+  // Pushes a function from the string library, loading it if needed.
+  fn push_string_lib_fn(&mut self, name: &str) {
+    self.requiref(Library::String.name(), Some(Library::String.loader()), false);
+    self.get_field(-1, name);
+    self.remove(-2);
+  }
+
   //===========================================================================
   // Coroutine functions
   //===========================================================================
-  /// Maps to `lua_yieldk`.
+  /// Maps to `lua_yieldk`. When called from a native function this function
+  /// never returns; the coroutine is suspended and `continuation` is invoked
+  /// with `ThreadStatus::Yield` once it is resumed. The return value of
+  /// `continuation` is the number of results returned by the native function.
+  ///
+  /// # Panics
+  ///
+  /// Panics if called from within a hook, where `lua_yieldk` returns normally.
+  /// Use `yield_with` instead in that case.
   pub fn co_yieldk<F>(&mut self, nresults: c_int, continuation: F) -> !
     where F: FnOnce(&mut State, ThreadStatus) -> c_int
   {
     unsafe { ffi::lua_yieldk(self.L, nresults, mem::transmute(Box::new(continuation)), Some(continue_func::<F>)) };
-    panic!("co_yieldk called in non-coroutine context; check is_yieldable first")
+    panic!("co_yieldk called from a hook; use yield_with instead")
   }
 
   /// Maps to `lua_yield`. This function is not called `yield` because it is a
-  /// reserved keyword.
+  /// reserved keyword. When called from a native function this function never
+  /// returns.
+  ///
+  /// # Panics
+  ///
+  /// Panics if called from within a hook, where `lua_yield` returns normally.
+  /// Use `yield_with` instead in that case.
   pub fn co_yield(&mut self, nresults: c_int) -> ! {
     unsafe { ffi::lua_yield(self.L, nresults) };
-    panic!("co_yield called in non-coroutine context; check is_yieldable first")
+    panic!("co_yield called from a hook; use yield_with instead")
+  }
+
+  /// Yields the running coroutine with the top `nresults` values on the stack.
+  ///
+  /// From a native function this behaves exactly like `co_yield` and does not
+  /// return. From within a hook `lua_yield` returns normally instead; this
+  /// function then returns `ThreadStatus::Yield` and the hook must return
+  /// immediately. Hooks may not yield any values, so `nresults` must be 0 in
+  /// that case.
+  pub fn yield_with(&mut self, nresults: c_int) -> ThreadStatus {
+    unsafe { ffi::lua_yield(self.L, nresults) };
+    ThreadStatus::Yield
+  }
+
+  /// Like `yield_with`, but checks `is_yieldable` first and returns
+  /// `YieldError::NotYieldable` instead of raising a Lua error when the
+  /// running coroutine cannot yield, e.g. on the main thread.
+  ///
+  /// From a native function a successful yield does not return; from a hook
+  /// it returns `Ok` with the result of `lua_yield` and the hook must return
+  /// immediately.
+  pub fn try_yield(&mut self, nresults: c_int) -> Result<c_int, YieldError> {
+    if !self.is_yieldable() {
+      return Err(YieldError::NotYieldable);
+    }
+    Ok(unsafe { ffi::lua_yield(self.L, nresults) })
   }
 
   /// Maps to `lua_resume`.
@@ -994,12 +2475,68 @@ impl State {
   //===========================================================================
   // Garbage-collection function
   //===========================================================================
-  // TODO: return typing?
-  /// Maps to `lua_gc`.
+  /// Maps to `lua_gc`. The meaning of the result depends on `what`; the
+  /// `gc_*` functions below provide typed results for each option.
   pub fn gc(&mut self, what: GcOption, data: c_int) -> c_int {
     unsafe { ffi::lua_gc(self.L, what as c_int, data) }
   }
 
+  /// Stops the garbage collector. Maps to `lua_gc` with `GcOption::Stop`.
+  pub fn gc_stop(&mut self) {
+    self.gc(GcOption::Stop, 0);
+  }
+
+  /// Restarts the garbage collector. Maps to `lua_gc` with
+  /// `GcOption::Restart`.
+  pub fn gc_restart(&mut self) {
+    self.gc(GcOption::Restart, 0);
+  }
+
+  /// Performs a full garbage-collection cycle. Maps to `lua_gc` with
+  /// `GcOption::Collect`.
+  pub fn gc_collect(&mut self) {
+    self.gc(GcOption::Collect, 0);
+  }
+
+  /// Returns the amount of memory in use by Lua in kilobytes. Maps to `lua_gc`
+  /// with `GcOption::Count`.
+  pub fn gc_count_kb(&mut self) -> c_int {
+    self.gc(GcOption::Count, 0)
+  }
+
+  /// Returns the amount of memory in use by Lua in bytes, combining the
+  /// results of `GcOption::Count` and `GcOption::CountBytes`.
+  pub fn gc_count_bytes(&mut self) -> usize {
+    let kb = self.gc(GcOption::Count, 0) as usize;
+    let rem = self.gc(GcOption::CountBytes, 0) as usize;
+    kb * 1024 + rem
+  }
+
+  /// Performs an incremental step of garbage collection of `kb` kilobytes.
+  /// Returns `true` if the step finished a collection cycle. Maps to `lua_gc`
+  /// with `GcOption::Step`.
+  pub fn gc_step(&mut self, kb: c_int) -> bool {
+    self.gc(GcOption::Step, kb) != 0
+  }
+
+  /// Sets the collector pause and returns its previous value. Maps to
+  /// `lua_gc` with `GcOption::SetPause`.
+  pub fn gc_set_pause(&mut self, pause: c_int) -> c_int {
+    self.gc(GcOption::SetPause, pause)
+  }
+
+  /// Sets the collector step multiplier and returns its previous value. Maps
+  /// to `lua_gc` with `GcOption::SetStepMul`.
+  pub fn gc_set_step_mul(&mut self, mul: c_int) -> c_int {
+    self.gc(GcOption::SetStepMul, mul)
+  }
+
+  /// Returns `true` if the collector is running. Maps to `lua_gc` with
+  /// `GcOption::IsRunning`.
+  pub fn gc_is_running(&mut self) -> bool {
+    self.gc(GcOption::IsRunning, 0) != 0
+  }
+
   //===========================================================================
   // Miscellaneous functions
   //===========================================================================
@@ -1015,12 +2552,290 @@ impl State {
     result != 0
   }
 
+  /// Iterates over the table at `index` using `lua_next`, calling `f` for each
+  /// entry with the key at index -2 and the value at index -1. The value is
+  /// popped after each call, so `f` must leave the stack as it found it.
+  ///
+  /// As with `next`, `f` must not convert the key in place (for example with
+  /// `to_str_in_place`) since that confuses the traversal; copy it with
+  /// `push_value` first.
+  pub fn for_each_pair<F>(&mut self, index: Index, mut f: F)
+    where F: FnMut(&mut State)
+  {
+    let index = self.abs_index(index);
+    self.push_nil();
+    while self.next(index) {
+      f(self);
+      self.pop(1);
+    }
+  }
+
+  /// Iterates over the table at `index` with `for_each_pair` and collects the
+  /// entries whose key and value both convert with `FromLua`; other entries
+  /// are skipped. Keys are converted from a copy, so conversions such as the
+  /// one for `String` do not disturb the traversal.
+  ///
+  /// Keys go through the same conversions as `to_type`, which may coerce them:
+  /// with `K = String`, the integer key `1` is collected as `"1"`. If a table
+  /// has both `1` and `"1"` as keys, the map gets a single entry for `"1"`
+  /// holding whichever value the traversal reaches last, which is
+  /// unspecified. Use a stricter key type such as `Integer` to keep only keys
+  /// of one Lua type.
+  pub fn collect_pairs<K, V>(&mut self, index: Index) -> BTreeMap<K, V>
+    where K: FromLua + Ord, V: FromLua
+  {
+    let mut map = BTreeMap::new();
+    self.for_each_pair(index, |state| {
+      state.push_value(-2);
+      let key = state.to_type::<K>(-1);
+      state.pop(1);
+      if let (Some(key), Some(value)) = (key, state.to_type::<V>(-1)) {
+        map.insert(key, value);
+      }
+    });
+    map
+  }
+
+  /// Returns the keys of the table at `index` in traversal order, converted
+  /// with `to_value`. Each value is popped as soon as it is reached, while the
+  /// key stays on the stack for the next call to `next`; `to_value` does not
+  /// convert it in place, so the traversal is not disturbed.
+  pub fn keys(&mut self, index: Index) -> Vec<LuaValue> {
+    let index = self.abs_index(index);
+    let mut keys = Vec::new();
+    self.push_nil();
+    while self.next(index) {
+      self.pop(1);
+      keys.push(self.to_value(-1));
+    }
+    keys
+  }
+
+  /// Iterates over the sequence in the table at `index` with `ipairs`
+  /// semantics, reading `t[1]`, `t[2]`, ... with `raw_geti` until the first
+  /// `nil`. `f` is called with each index and the value on the top of the
+  /// stack; the value is popped after each call, so `f` must leave the stack
+  /// as it found it.
+  pub fn for_each_index<F>(&mut self, index: Index, mut f: F)
+    where F: FnMut(&mut State, Integer)
+  {
+    let index = self.abs_index(index);
+    let mut i = 1;
+    while self.raw_geti(index, i) != Type::Nil {
+      f(self, i);
+      self.pop(1);
+      i += 1;
+    }
+    self.pop(1);
+  }
+
+  /// Reads the sequence `t[1]` to `t[#t]` of the table at `index` into a
+  /// vector, converting each element with `FromLua`. The length is taken with
+  /// `raw_len` and elements are read with `raw_geti`, so metamethods are not
+  /// invoked. Returns `None` if the value is not a table or any element fails
+  /// to convert; an empty table produces an empty vector.
+  pub fn to_vec<T: FromLua>(&mut self, index: Index) -> Option<Vec<T>> {
+    if !self.is_table(index) {
+      return None;
+    }
+    let index = self.abs_index(index);
+    let len = self.raw_len(index) as Integer;
+    let mut values = Vec::with_capacity(len as usize);
+    for i in 1..len + 1 {
+      self.raw_geti(index, i);
+      let value = self.to_type::<T>(-1);
+      self.pop(1);
+      match value {
+        Some(value) => values.push(value),
+        None => return None
+      }
+    }
+    Some(values)
+  }
+
+  /// Copies the value at `index` into a `LuaValue`. Tables are read one level
+  /// deep; nested tables are returned as `LuaValue::Other(Type::Table)`. Use
+  /// `to_value_depth` to read further. Metamethods are not invoked.
+  pub fn to_value(&mut self, index: Index) -> LuaValue {
+    self.to_value_depth(index, 1)
+  }
+
+  /// Like `to_value`, but reads nested tables up to `depth` levels. A depth of
+  /// 0 does not read any table contents, which also bounds the traversal of
+  /// tables that refer to themselves.
+  pub fn to_value_depth(&mut self, index: Index, depth: usize) -> LuaValue {
+    match self.type_of(index) {
+      None | Some(Type::Nil) => LuaValue::Nil,
+      Some(Type::Boolean) => LuaValue::Bool(self.to_bool(index)),
+      Some(Type::Number) => {
+        if self.is_integer(index) {
+          LuaValue::Integer(self.to_integer(index))
+        } else {
+          LuaValue::Number(self.to_number(index))
+        }
+      }
+      Some(Type::String) => {
+        LuaValue::Str(self.to_bytes_in_place(index).map(ToOwned::to_owned).unwrap_or_default())
+      }
+      Some(Type::Function) => LuaValue::Function,
+      Some(Type::Table) if depth > 0 => {
+        let mut pairs = Vec::new();
+        self.for_each_pair(index, |state| {
+          let key = state.to_value_depth(-2, depth - 1);
+          let value = state.to_value_depth(-1, depth - 1);
+          pairs.push((key, value));
+        });
+        LuaValue::Table(pairs)
+      }
+      Some(ty) => LuaValue::Other(ty)
+    }
+  }
+
+  /// Renders the value at `index` as a readable string for debugging,
+  /// independently of Lua's `print` and any `__tostring` metamethods.
+  ///
+  /// Strings are quoted and escaped, and floats always show a fractional part
+  /// or exponent so they can be told apart from integers. Tables are expanded
+  /// recursively, one entry per line and indented by two spaces per level,
+  /// with integer keys first in ascending order and the remaining keys sorted
+  /// by their rendering. A table that contains itself, directly or through
+  /// nested tables, is shown as `<cycle>` where it recurs. Other values are
+  /// shown with their type name and address.
+  pub fn dump_value(&mut self, index: Index) -> String {
+    let index = self.abs_index(index);
+    let mut out = String::new();
+    self.dump_value_into(index, 0, &mut Vec::new(), &mut out);
+    out
+  }
+
+  /// Returns a snapshot of the whole stack for debugging: the index, type
+  /// and `to_string_meta` representation of every slot from 1 to `get_top()`.
+  /// The stack is left unchanged, but `__tostring` metamethods are called and
+  /// may raise errors.
+  pub fn dump_stack(&mut self) -> Vec<(Index, Type, String)> {
+    let top = self.get_top();
+    let mut slots = Vec::with_capacity(top as usize);
+    for i in 1..top + 1 {
+      let ty = self.type_of(i).unwrap_or(Type::None);
+      slots.push((i, ty, self.to_string_meta(i)));
+    }
+    slots
+  }
+
+  fn dump_value_into(&mut self, index: Index, depth: usize, path: &mut Vec<*const c_void>, out: &mut String) {
+    match self.type_of(index) {
+      None | Some(Type::Nil) => out.push_str("nil"),
+      Some(Type::Boolean) => out.push_str(if self.to_bool(index) { "true" } else { "false" }),
+      Some(Type::Number) if self.is_integer(index) => out.push_str(&self.to_integer(index).to_string()),
+      Some(Type::Number) => out.push_str(&format!("{:?}", self.to_number(index))),
+      Some(Type::String) => {
+        let s = String::from_utf8_lossy(self.to_bytes_in_place(index).unwrap_or(b"")).into_owned();
+        out.push_str(&format!("{:?}", s));
+      }
+      Some(Type::Table) => {
+        let ptr = self.to_pointer(index);
+        if path.contains(&ptr) {
+          out.push_str("<cycle>");
+          return;
+        }
+        if !self.check_stack(3) {
+          out.push_str("<too deep>");
+          return;
+        }
+        path.push(ptr);
+        // (integer key first?, integer key, rendered key, rendered value)
+        let mut entries = Vec::new();
+        self.for_each_pair(index, |state| {
+          let top = state.get_top();
+          let mut key = String::new();
+          // only string keys may be read in place without disturbing `next`
+          let name = if state.type_of(top - 1) == Some(Type::String) {
+            state.to_bytes_in_place(top - 1).and_then(identifier).map(ToOwned::to_owned)
+          } else {
+            None
+          };
+          let order = if state.is_integer(top - 1) {
+            key.push_str(&format!("[{}]", state.to_integer(top - 1)));
+            (0, state.to_integer(top - 1))
+          } else if let Some(name) = name {
+            key.push_str(&name);
+            (1, 0)
+          } else {
+            key.push('[');
+            state.dump_value_into(top - 1, depth + 1, path, &mut key);
+            key.push(']');
+            (1, 0)
+          };
+          let mut value = String::new();
+          state.dump_value_into(top, depth + 1, path, &mut value);
+          entries.push((order, key, value));
+        });
+        path.pop();
+        if entries.is_empty() {
+          out.push_str("{}");
+          return;
+        }
+        entries.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        out.push_str("{\n");
+        for (_, key, value) in entries {
+          push_indent(out, depth + 1);
+          out.push_str(&key);
+          out.push_str(" = ");
+          out.push_str(&value);
+          out.push_str(",\n");
+        }
+        push_indent(out, depth);
+        out.push('}');
+      }
+      Some(ty) => {
+        let ptr = self.to_pointer(index);
+        out.push_str(&format!("{}: {:p}", self.typename_of(ty), ptr));
+      }
+    }
+
+    fn identifier(bytes: &[u8]) -> Option<&str> {
+      let s = match str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(_) => return None,
+      };
+      let mut chars = s.chars();
+      match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => {}
+        _ => return None,
+      }
+      if chars.all(|c| c == '_' || c.is_ascii_alphanumeric()) {
+        Some(s)
+      } else {
+        None
+      }
+    }
+
+    fn push_indent(out: &mut String, depth: usize) {
+      for _ in 0..depth {
+        out.push_str("  ");
+      }
+    }
+  }
+
   /// Maps to `lua_concat`.
   pub fn concat(&mut self, n: c_int) {
     unsafe { ffi::lua_concat(self.L, n) }
   }
 
-  /// Maps to `lua_len`.
+  /// Concatenates the `n` values on the top of the stack like `concat`, pops
+  /// the result and returns it as a string. Invalid UTF-8 is replaced, and a
+  /// `__concat` metamethod that returns something other than a string or
+  /// number yields its `__tostring` representation, as with `to_string_meta`.
+  pub fn concat_to_string(&mut self, n: c_int) -> String {
+    self.concat(n);
+    let result = self.to_string_meta(-1);
+    self.pop(1);
+    result
+  }
+
+  /// Maps to `lua_len`. Pushes the length of the value at `idx`, as the `#`
+  /// operator would, which may invoke `__len` and produce any value. See
+  /// `length` for a version that returns an integer directly.
   pub fn len(&mut self, idx: Index) {
     unsafe { ffi::lua_len(self.L, idx) }
   }
@@ -1042,20 +2857,47 @@ impl State {
     unsafe { ffi::lua_setallocf(self.L, f, ud) }
   }
 
+  fn alloc_stats(&mut self) -> Option<&mut AllocStats> {
+    let (f, ud) = self.get_alloc_fn();
+    if f.map(|f| f as *const ()) == Some(counting_alloc_func as *const ()) {
+      Some(unsafe { &mut *(ud as *mut AllocStats) })
+    } else {
+      None
+    }
+  }
+
+  /// Returns the number of bytes currently allocated by a state created with
+  /// `State::new_counting`, or `None` for other states.
+  pub fn allocated_bytes(&mut self) -> Option<usize> {
+    self.alloc_stats().map(|stats| stats.allocated)
+  }
+
+  /// Returns the largest number of bytes allocated at once by a state created
+  /// with `State::new_counting`, or `None` for other states.
+  pub fn peak_bytes(&mut self) -> Option<usize> {
+    self.alloc_stats().map(|stats| stats.peak)
+  }
+
   //===========================================================================
   // Some useful macros (here implemented as functions)
   //===========================================================================
 
   /// Set extra data. Return previous value if it was set.
+  ///
+  /// All threads of a state share the same slot, so setting the extra data
+  /// from a thread created with `new_thread` replaces it for the main thread
+  /// too. To attach data of several types at once, see `set_app_data`.
   pub fn set_extra(&mut self, extra: Option<Extra>) -> Option<Extra> {
     self.with_extra(|opt_extra| mem::replace(opt_extra, extra))
   }
 
-  /// Do some actions with mutable extra.
+  /// Do some actions with mutable extra. The slot is locked while `closure`
+  /// runs, so `closure` must not call `with_extra` or `set_extra` again.
   pub fn with_extra<F, R>(&mut self, closure: F) -> R
     where F: FnOnce(&mut Option<Extra>) -> R {
     unsafe {
       let extra_ptr = ffi::lua_getextraspace(self.L) as ExtraHolder;
+      assert!(!(*extra_ptr).is_null(), "extra data is not supported by states created with new_native");
       let mutex = Box::from_raw(*extra_ptr);
       let result = {
         let mut guard = mutex.lock().unwrap();
@@ -1081,6 +2923,49 @@ impl State {
     })
   }
 
+  /// Stores `value` as the application data of type `T`, returning the value
+  /// previously stored for `T`. Unlike the single `Extra` slot, each type gets
+  /// its own entry, so independent libraries can keep their data in the same
+  /// state. The data lives in the registry and is shared by all threads of
+  /// the state; it is dropped when the state is closed. Like `Extra`, the
+  /// data must be `Send`, since the state may be sent to another thread.
+  pub fn set_app_data<T: Send + 'static>(&mut self, value: T) -> Option<T> {
+    let previous = self.app_data_map(true).unwrap()
+      .insert(any::TypeId::of::<T>(), Box::new(value));
+    previous.map(|previous| *previous.downcast::<T>().ok().unwrap())
+  }
+
+  /// Returns the application data of type `T` stored with `set_app_data`.
+  pub fn app_data<'a, T: Send + 'static>(&'a mut self) -> Option<&'a mut T> {
+    self.app_data_map(false)
+      .and_then(|map| map.get_mut(&any::TypeId::of::<T>()))
+      .and_then(|value| value.downcast_mut::<T>())
+  }
+
+  /// Removes and returns the application data of type `T`.
+  pub fn remove_app_data<T: Send + 'static>(&mut self) -> Option<T> {
+    self.app_data_map(false)
+      .and_then(|map| map.remove(&any::TypeId::of::<T>()))
+      .map(|value| *value.downcast::<T>().ok().unwrap())
+  }
+
+  fn app_data_map<'a>(&'a mut self, create: bool) -> Option<&'a mut AppData> {
+    let key = &APP_DATA_KEY as *const u8;
+    if self.raw_getp(REGISTRYINDEX, key) == Type::Nil {
+      self.pop(1);
+      if !create {
+        return None;
+      }
+      self.push_boxed(AppData::new());
+      self.push_value(-1);
+      self.raw_setp(REGISTRYINDEX, key);
+    }
+    let map = self.to_userdata(-1) as *mut Box<AppData>;
+    self.pop(1);
+    // the registry keeps the userdata alive for the lifetime of the state
+    unsafe { map.as_mut().map(|map| &mut **map) }
+  }
+
   /// Maps to `lua_tonumber`.
   pub fn to_number(&mut self, index: Index) -> Number {
     unsafe { ffi::lua_tonumber(self.L, index) }
@@ -1107,6 +2992,43 @@ impl State {
     unsafe { ffi::lua_register(self.L, c_str.as_ptr(), f) }
   }
 
+  /// Sets the native function `f` as the global `name`. This is the same as
+  /// `register`, under a name that says what it does.
+  pub fn set_global_fn(&mut self, name: &str, f: Function) {
+    self.push_fn(f);
+    self.set_global(name);
+  }
+
+  /// Pushes `value` and sets it as the global `name`.
+  pub fn set_global_value<T: ToLua>(&mut self, name: &str, value: T) {
+    value.to_lua(self);
+    self.set_global(name);
+  }
+
+  /// Reads the global `name` and converts it with `FromLua`, leaving the
+  /// stack unchanged. Returns `None` if the global is missing (even for types
+  /// such as `Option<T>` that accept `nil`) or does not convert to `T`. Note
+  /// that `String` accepts any value, so a table global is returned as
+  /// something like `"table: 0x..."`.
+  pub fn global<T: FromLua>(&mut self, name: &str) -> Option<T> {
+    let value = match self.get_global(name) {
+      Type::Nil => None,
+      _ => self.to_type(-1),
+    };
+    self.pop(1);
+    value
+  }
+
+  /// Convenience function that calls `push_closure_boxed` and sets the
+  /// resulting closure as the global `n`. The same requirements apply to
+  /// `f`.
+  pub fn register_closure<F>(&mut self, n: &str, f: F)
+    where F: FnMut(&mut State) -> c_int + Send + 'static
+  {
+    self.push_closure_boxed(f);
+    self.set_global(n)
+  }
+
   /// Maps to `lua_pushcfunction`.
   pub fn push_fn(&mut self, f: Function) {
     unsafe { ffi::lua_pushcfunction(self.L, f) }
@@ -1117,6 +3039,20 @@ impl State {
     unsafe { ffi::lua_isfunction(self.L, index) == 1 }
   }
 
+  /// Returns `true` if the value at `index` can be called: a function, or a
+  /// value whose metatable has a `__call` field holding a function.
+  pub fn is_callable(&mut self, index: Index) -> bool {
+    if self.is_fn(index) {
+      return true;
+    }
+    if !self.get_metafield(index, "__call") {
+      return false;
+    }
+    let callable = self.is_fn(-1);
+    self.pop(1);
+    callable
+  }
+
   /// Maps to `lua_istable`.
   pub fn is_table(&mut self, index: Index) -> bool {
     unsafe { ffi::lua_istable(self.L, index) == 1 }
@@ -1200,6 +3136,67 @@ impl State {
     }
   }
 
+  /// Returns information about the function running at the given call
+  /// `level` (0 is the current function), combining `lua_getstack` and
+  /// `lua_getinfo`. Returns `None` if `level` is greater than the stack depth.
+  pub fn get_stack_info(&mut self, level: c_int, what: &str) -> Option<DebugInfo> {
+    let mut ar: lua_Debug = unsafe { mem::zeroed() };
+    if unsafe { ffi::lua_getstack(self.L, level, &mut ar) } == 0 {
+      return None;
+    }
+    let c_str = CString::new(what).unwrap();
+    unsafe { ffi::lua_getinfo(self.L, c_str.as_ptr(), &mut ar) };
+    Some(DebugInfo::from(&ar))
+  }
+
+  /// Walks the call stack from `level` outwards and returns the name, source
+  /// and current line of each frame, innermost first. This is the structured
+  /// counterpart of `traceback_string`; called from a message handler with
+  /// `level` 1 or 2, it describes the frames that raised the error.
+  pub fn collect_traceback(&mut self, level: c_int) -> Vec<DebugInfo> {
+    let mut frames = Vec::new();
+    let mut level = level;
+    while let Some(info) = self.stack_frame_info(level, "") {
+      frames.push(info);
+      level += 1;
+    }
+    frames
+  }
+
+  /// Like `get_stack_info`, but an empty `what` selects `"nSl"`: the name,
+  /// source and current line, which are the fields usually wanted for a
+  /// stack frame.
+  pub fn stack_frame_info(&mut self, level: c_int, what: &str) -> Option<DebugInfo> {
+    let what = if what.is_empty() { "nSl" } else { what };
+    self.get_stack_info(level, what)
+  }
+
+  /// Pops the function on the top of the stack and returns information about
+  /// it, using the `>` form of `lua_getinfo`. `what` selects the fields to
+  /// fill in.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the value on the top of the stack is not a function, if
+  /// `what` starts with `>`, or if `what` contains `f` or `L`, which would
+  /// make `lua_getinfo` push extra values; call `get_info` with the `>` form
+  /// directly when those are needed.
+  pub fn get_info_for_function(&mut self, what: &str) -> DebugInfo {
+    if !self.is_fn(-1) {
+      panic!("get_info_for_function called without a function on the top of the stack");
+    }
+    if what.starts_with('>') {
+      panic!("get_info_for_function: `what` must not start with `>`");
+    }
+    if what.contains(|c| c == 'f' || c == 'L') {
+      panic!("get_info_for_function: `what` must not contain `f` or `L`");
+    }
+    let mut ar: lua_Debug = unsafe { mem::zeroed() };
+    let c_str = CString::new(format!(">{}", what)).unwrap();
+    unsafe { ffi::lua_getinfo(self.L, c_str.as_ptr(), &mut ar) };
+    DebugInfo::from(&ar)
+  }
+
   /// Maps to `lua_getlocal`.
   pub fn get_local(&mut self, ar: &lua_Debug, n: c_int) -> Option<&str> {
     let ptr = unsafe { ffi::lua_getlocal(self.L, ar, n) };
@@ -1244,6 +3241,24 @@ impl State {
     }
   }
 
+  /// Replaces the environment of the chunk at `chunk_index` with the table at
+  /// `env_index`, so that the chunk's global accesses go to that table
+  /// instead of the real globals. This sets the first upvalue, which is
+  /// `_ENV` for functions returned by `load` and friends; it should not be
+  /// used on other functions. Returns `false`, leaving the stack unchanged,
+  /// if the function has no upvalues.
+  pub fn set_chunk_env(&mut self, chunk_index: Index, env_index: Index) -> bool {
+    let chunk_index = self.abs_index(chunk_index);
+    self.push_value(env_index);
+    let name = unsafe { ffi::lua_setupvalue(self.L, chunk_index, 1) };
+    if name.is_null() {
+      self.pop(1);
+      false
+    } else {
+      true
+    }
+  }
+
   /// Maps to `lua_upvalueid`.
   pub fn upvalue_id(&mut self, funcindex: Index, n: c_int) -> *mut c_void {
     unsafe { ffi::lua_upvalueid(self.L, funcindex, n) }
@@ -1275,6 +3290,95 @@ impl State {
     unsafe { ffi::lua_gethookcount(self.L) }
   }
 
+  /// Installs `f` as the hook for this thread, to be called for the events in
+  /// `mask` as described in `lua_sethook`. Unlike `set_hook`, `f` may be any
+  /// Rust closure. It is stored in the registry and dropped once the hook is
+  /// replaced through `set_hook_fn` or removed through `remove_hook` and the
+  /// garbage collector runs. `f` may raise an error with `error` or
+  /// `raise_error` to abort the running code.
+  ///
+  /// There is one such closure per Lua state, shared by all of its threads.
+  /// Threads created afterwards, including coroutines created from Lua,
+  /// inherit the hook and call the same closure, and calling this function
+  /// on any thread replaces the closure for all of them. While `f` runs, the
+  /// stack holds one extra value, which `f` must not remove.
+  ///
+  /// Like `push_closure_boxed`, `f` must be `Send` and must not cause itself
+  /// to be called again while it is running, for example by resuming a
+  /// coroutine from within the hook.
+  pub fn set_hook_fn<F>(&mut self, mask: HookMask, count: c_int, f: F)
+    where F: FnMut(&mut State, &lua_Debug) + Send + 'static
+  {
+    #[allow(non_snake_case)]
+    extern "C" fn hook_boxed(L: *mut lua_State, ar: *mut lua_Debug) {
+      unsafe {
+        let mut state = State::from_ptr(L);
+        state.raw_getp(REGISTRYINDEX, &HOOK_KEY as *const u8);
+        let f = state.to_userdata(-1) as *mut Box<HookFn>;
+        if f.is_null() {
+          state.pop(1);
+          return;
+        }
+        // keep the closure alive on the stack in case it replaces itself
+        (**f)(&mut state, &*ar);
+        state.pop(1);
+      }
+    }
+    let f: HookFn = Box::new(f);
+    self.push_boxed(f);
+    self.raw_setp(REGISTRYINDEX, &HOOK_KEY as *const u8);
+    self.set_hook(Some(hook_boxed), mask, count);
+  }
+
+  /// Removes the hook from this thread and drops the closure installed with
+  /// `set_hook_fn` once the garbage collector runs. Other threads that share
+  /// the closure keep their hook, but it no longer does anything.
+  pub fn remove_hook(&mut self) {
+    self.set_hook(None, HookMask::empty(), 0);
+    self.push_nil();
+    self.raw_setp(REGISTRYINDEX, &HOOK_KEY as *const u8);
+  }
+
+  /// Installs a count hook that calls `f` every `every` instructions. This is
+  /// a shorthand for `set_hook_fn` with `MASKCOUNT`.
+  pub fn set_count_hook(&mut self, every: c_int, f: fn(&mut State)) {
+    self.set_hook_fn(MASKCOUNT, every, move |state, _| f(state));
+  }
+
+  /// Installs a count hook that raises the error "instruction limit exceeded"
+  /// once roughly `max` VM instructions have been executed. Coroutines
+  /// created afterwards inherit the hook and draw from the same budget, so
+  /// scripts cannot escape the limit by running code inside a coroutine.
+  /// Instructions are counted in batches of up to 1000, so the limit is
+  /// approximate. The budget is not replenished automatically; call this
+  /// function again to reset it.
+  pub fn limit_instructions(&mut self, max: u64) {
+    let every = cmp::max(1, cmp::min(max, 1000));
+    let mut remaining = max;
+    self.set_hook_fn(MASKCOUNT, every as c_int, move |state, _| {
+      remaining = remaining.saturating_sub(every);
+      if remaining == 0 {
+        state.raise_error("instruction limit exceeded");
+      }
+    });
+  }
+
+  /// Installs a count hook that raises the error "execution deadline
+  /// exceeded" once `duration` has passed since this function was called. The
+  /// clock is checked every 1000 VM instructions, so the deadline may be
+  /// overrun slightly, and time spent inside native functions is not
+  /// interrupted. Like `limit_instructions`, the deadline also applies to
+  /// coroutines created afterwards, and this replaces any closure installed
+  /// with `set_hook_fn`.
+  pub fn set_deadline(&mut self, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    self.set_hook_fn(MASKCOUNT, 1000, move |state, _| {
+      if Instant::now() >= deadline {
+        state.raise_error("execution deadline exceeded");
+      }
+    });
+  }
+
   //===========================================================================
   // Auxiliary library functions
   //===========================================================================
@@ -1316,6 +3420,22 @@ impl State {
     }
   }
 
+  /// Converts the value at the given index to a string using `luaL_tolstring`,
+  /// which respects the `__tostring` metamethod and leaves the original value
+  /// untouched. Unlike `to_str`, the resulting string is popped off the stack
+  /// and returned as an owned `String`. Invalid UTF-8 sequences are replaced
+  /// with `U+FFFD REPLACEMENT CHARACTER`.
+  pub fn to_string_meta(&mut self, index: Index) -> String {
+    let mut len = 0;
+    let result = unsafe {
+      let ptr = ffi::luaL_tolstring(self.L, index, &mut len);
+      let slice = slice::from_raw_parts(ptr as *const u8, len as usize);
+      String::from_utf8_lossy(slice).into_owned()
+    };
+    self.pop(1);
+    result
+  }
+
   /// Maps to `lua_tolstring`. This function is not called `to_string` because
   /// that method name is used for the `ToString` trait. This function returns
   /// a reference to the string at the given index, on which `to_owned` may be
@@ -1331,6 +3451,14 @@ impl State {
     }
   }
 
+  /// Maps to `lua_tolstring`, replacing any invalid UTF-8 sequences with
+  /// `U+FFFD REPLACEMENT CHARACTER`. Returns `None` only if the value is
+  /// neither a string nor a number. Like `to_str_in_place`, numbers are
+  /// converted to strings in place.
+  pub fn to_str_lossy<'a>(&'a mut self, index: Index) -> Option<Cow<'a, str>> {
+    self.to_bytes_in_place(index).map(String::from_utf8_lossy)
+  }
+
   /// Maps to `lua_tolstring`, but allows arbitrary bytes.
   /// This function returns a reference to the string at the given index,
   /// on which `to_owned` may be called.
@@ -1345,6 +3473,38 @@ impl State {
     }
   }
 
+  /// Raises an argument error unless exactly `expected` arguments were
+  /// passed, for use at the start of a native function. The error blames the
+  /// first missing or extra argument, e.g. `bad argument #3 to 'f' (expected
+  /// 2 arguments, got 3)`.
+  pub fn check_arg_count(&mut self, expected: c_int) {
+    self.check_arg_range(expected, expected)
+  }
+
+  /// Raises an argument error unless between `min` and `max` arguments
+  /// (inclusive) were passed. See `check_arg_count`.
+  pub fn check_arg_range(&mut self, min: c_int, max: c_int) {
+    let top = self.get_top();
+    if top >= min && top <= max {
+      return;
+    }
+    let arg = if top < min { top + 1 } else { max + 1 };
+    // luaL_argerror does not return, so the message is formatted into a
+    // buffer on the stack rather than an allocation that would leak
+    let mut buf = [0u8; 64];
+    {
+      let mut cursor = io::Cursor::new(&mut buf[..63]);
+      let plural = if max == 1 { "" } else { "s" };
+      let _ = if min == max {
+        io::Write::write_fmt(&mut cursor, format_args!("expected {} argument{}, got {}", min, plural, top))
+      } else {
+        io::Write::write_fmt(&mut cursor, format_args!("expected {} to {} arguments, got {}", min, max, top))
+      };
+    }
+    unsafe { ffi::luaL_argerror(self.L, arg, buf.as_ptr() as *const c_char) };
+    unreachable!()
+  }
+
   /// Maps to `luaL_argerror`.
   pub fn arg_error(&mut self, arg: Index, extramsg: &str) -> ! {
     // nb: leaks the CString
@@ -1401,6 +3561,16 @@ impl State {
     result != 0
   }
 
+  /// Starts building a metatable named `tname` in the registry, for use with
+  /// `set_metatable_from_registry` and `check_userdata`.
+  pub fn metatable<'a>(&'a mut self, tname: &str) -> MetatableBuilder<'a> {
+    MetatableBuilder {
+      state: self,
+      name: tname.to_owned(),
+      fields: Vec::new(),
+    }
+  }
+
   /// Maps to `luaL_setmetatable`.
   pub fn set_metatable_from_registry(&mut self, tname: &str) {
     let c_str = CString::new(tname).unwrap();
@@ -1436,8 +3606,36 @@ impl State {
     unsafe { ffi::luaL_where(self.L, lvl) }
   }
 
+  /// Like `location`, but pops the location and returns it. The result has
+  /// the form `chunkname:currentline: `, or is empty if no location
+  /// information is available for level `lvl`.
+  pub fn where_string(&mut self, lvl: c_int) -> String {
+    self.location(lvl);
+    self.pop_error()
+  }
+
   // omitted: luaL_error
 
+  /// Raises a Lua error with `msg` as the error object. This takes the place
+  /// of `luaL_error`, except that no location information is added to the
+  /// message; see also the `lua_error!` macro.
+  pub fn raise_error(&mut self, msg: &str) -> ! {
+    self.push_string(msg);
+    self.error()
+  }
+
+  /// Raises a Lua error with `msg` prefixed by the location of level `lvl`,
+  /// as given by `where_string`. With `lvl` 1 this behaves like
+  /// `luaL_error`, pointing at the Lua code that called the native function.
+  pub fn raise_error_at(&mut self, lvl: c_int, msg: &str) -> ! {
+    // build the message on the Lua stack; `error` does not return, so a Rust
+    // string would never be freed
+    self.location(lvl);
+    self.push_string(msg);
+    self.concat(2);
+    self.error()
+  }
+
   /// Maps to `luaL_checkoption`.
   pub fn check_option(&mut self, arg: Index, def: Option<&str>, lst: &[&str]) -> usize {
     use std::vec::Vec;
@@ -1483,9 +3681,11 @@ impl State {
   }
 
   /// Maps to `luaL_loadfilex`.
-  pub fn load_filex(&mut self, filename: &str, mode: &str) -> ThreadStatus {
+  ///
+  /// See `load_file` for how `filename` is converted.
+  pub fn load_filex<P: AsRef<Path>>(&mut self, filename: P, mode: &str) -> ThreadStatus {
     let result = unsafe {
-      let filename_c_str = CString::new(filename).unwrap();
+      let filename_c_str = path_to_c_string(filename.as_ref());
       let mode_c_str = CString::new(mode).unwrap();
       ffi::luaL_loadfilex(self.L, filename_c_str.as_ptr(), mode_c_str.as_ptr())
     };
@@ -1493,8 +3693,17 @@ impl State {
   }
 
   /// Maps to `luaL_loadfile`.
-  pub fn load_file(&mut self, filename: &str) -> ThreadStatus {
-    let c_str = CString::new(filename).unwrap();
+  ///
+  /// On Unix the path is passed to Lua as its raw bytes, so non-UTF-8 paths
+  /// work. Elsewhere Lua opens files with `fopen`, which interprets the name
+  /// in the current code page; the path is converted lossily to UTF-8 first,
+  /// so names that are not representable there cannot be opened.
+  ///
+  /// # Panics
+  ///
+  /// Panics if the path contains a nul byte.
+  pub fn load_file<P: AsRef<Path>>(&mut self, filename: P) -> ThreadStatus {
+    let c_str = path_to_c_string(filename.as_ref());
     let result = unsafe {
       ffi::luaL_loadfile(self.L, c_str.as_ptr())
     };
@@ -1523,6 +3732,15 @@ impl State {
     unsafe { ffi::luaL_len(self.L, index) }
   }
 
+  /// Returns the length of the value at `index` as the `#` operator would,
+  /// honoring the `__len` metamethod, without leaving anything on the stack.
+  /// Raises an error if the length is not an integer. This is the same as
+  /// `len_direct`, and unlike `raw_len` it sees metamethods; unlike `len` it
+  /// does not push the result.
+  pub fn length(&mut self, index: Index) -> Integer {
+    self.len_direct(index)
+  }
+
   /// Maps to `luaL_gsub`.
   pub fn gsub(&mut self, s: &str, p: &str, r: &str) -> &str {
     let s_c_str = CString::new(s).unwrap();
@@ -1559,18 +3777,87 @@ impl State {
     result != 0
   }
 
+  /// Ensures that `t[key]` is a table, where `t` is the table at `parent`,
+  /// and pushes it onto the stack. A fresh table is created and assigned if
+  /// the field is not already a table. Returns `true` if the table was newly
+  /// created.
+  ///
+  /// This is the inverse of `get_subtable` with respect to its return value and
+  /// is useful for building nested module tables like `a.b.c`.
+  pub fn get_or_create_table(&mut self, parent: Index, key: &str) -> bool {
+    !self.get_subtable(parent, key)
+  }
+
+  /// Pops the value on the top of the stack and assigns it to the dotted
+  /// `path` (such as `"app.window.width"`) starting from the global table.
+  /// Intermediate fields that are not tables are replaced by new tables, as
+  /// with `get_or_create_table`. The final assignment may trigger
+  /// metamethods.
+  pub fn set_path(&mut self, path: &str) {
+    let value = self.abs_index(-1);
+    let (parents, last) = match path.rfind('.') {
+      Some(i) => (&path[..i], &path[i + 1..]),
+      None => ("", path),
+    };
+    self.push_global_table();
+    for key in parents.split('.').filter(|key| !key.is_empty()) {
+      self.get_or_create_table(-1, key);
+      self.remove(-2);
+    }
+    self.push_value(value);
+    self.set_field(-2, last);
+    self.pop(2);
+  }
+
+  /// Pushes the value at the dotted `path` starting from the global table and
+  /// returns its type. If a segment is missing or is not a table, `nil` is
+  /// pushed instead.
+  pub fn get_path(&mut self, path: &str) -> Type {
+    self.push_global_table();
+    for key in path.split('.') {
+      if !self.is_table(-1) {
+        self.pop(1);
+        self.push_nil();
+        return Type::Nil;
+      }
+      self.get_field(-1, key);
+      self.remove(-2);
+    }
+    self.type_of(-1).unwrap()
+  }
+
   /// Maps to `luaL_traceback`.
   pub fn traceback(&mut self, state: &mut State, msg: &str, level: c_int) {
     let c_str = CString::new(msg).unwrap();
     unsafe { ffi::luaL_traceback(self.L, state.L, c_str.as_ptr(), level) }
   }
 
+  /// Like `traceback`, but pops the traceback and returns it instead of
+  /// leaving it on the stack. To trace the thread of `self`, pass a second
+  /// wrapper obtained with `from_ptr(self.as_ptr())`.
+  pub fn traceback_string(&mut self, thread: &mut State, msg: &str, level: c_int) -> String {
+    self.traceback(thread, msg, level);
+    self.pop_error()
+  }
+
   /// Maps to `luaL_requiref`.
   pub fn requiref(&mut self, modname: &str, openf: Function, glb: bool) {
     let c_str = CString::new(modname).unwrap();
     unsafe { ffi::luaL_requiref(self.L, c_str.as_ptr(), openf, glb as c_int) }
   }
 
+  /// Registers `openf` as the loader of module `modname` in
+  /// `package.preload`, so that it only runs when a script first calls
+  /// `require(modname)`; unlike `requiref`, nothing is loaded eagerly. The
+  /// preload table lives in the registry under `_PRELOAD`, so this works
+  /// whether or not the `package` library has been opened yet.
+  pub fn preload_module(&mut self, modname: &str, openf: Function) {
+    self.get_subtable(REGISTRYINDEX, "_PRELOAD");
+    self.push_fn(openf);
+    self.set_field(-2, modname);
+    self.pop(1);
+  }
+
   /// Maps to `luaL_newlibtable`.
   pub fn new_lib_table(&mut self, l: &[(&str, Function)]) {
     self.create_table(0, l.len() as c_int)
@@ -1599,6 +3886,43 @@ impl State {
     str::from_utf8(slice).unwrap()
   }
 
+  /// Maps to `luaL_checklstring` without copying the string. Unlike
+  /// `check_string`, which panics on invalid UTF-8, this raises an argument
+  /// error instead, so it is safe to use in native functions that only
+  /// inspect their argument.
+  ///
+  /// The returned slice points into Lua's copy of the string and is only
+  /// valid while that string stays on the stack. The borrow of `self` prevents
+  /// popping it through this `State`, but not through another `State` for the
+  /// same thread.
+  pub fn check_str(&mut self, arg: Index) -> &str {
+    let mut size = 0;
+    let ptr = unsafe { ffi::luaL_checklstring(self.L, arg, &mut size) };
+    let slice = unsafe { slice::from_raw_parts(ptr as *const u8, size as usize) };
+    match str::from_utf8(slice) {
+      Ok(s) => s,
+      Err(_) => {
+        // a static message, since luaL_argerror does not return
+        let msg = b"invalid UTF-8\0";
+        unsafe { ffi::luaL_argerror(self.L, arg, msg.as_ptr() as *const c_char) };
+        unreachable!()
+      }
+    }
+  }
+
+  /// Maps to `luaL_checklstring`, but allows arbitrary bytes.
+  pub fn check_bytes(&mut self, n: Index) -> &[u8] {
+    let mut size = 0;
+    let ptr = unsafe { ffi::luaL_checklstring(self.L, n, &mut size) };
+    unsafe { slice::from_raw_parts(ptr as *const u8, size as usize) }
+  }
+
+  /// Maps to `luaL_checklstring`, replacing any invalid UTF-8 sequences with
+  /// `U+FFFD REPLACEMENT CHARACTER` instead of panicking like `check_string`.
+  pub fn check_string_lossy<'a>(&'a mut self, n: Index) -> Cow<'a, str> {
+    String::from_utf8_lossy(self.check_bytes(n))
+  }
+
   /// Maps to `luaL_optlstring`.
   pub fn opt_string<'a>(&'a mut self, n: Index, default: &'a str) -> &'a str {
     let mut size = 0;
@@ -1612,6 +3936,28 @@ impl State {
     }
   }
 
+  /// Like `opt_string`, but allows arbitrary bytes in both the argument and
+  /// `default`. Returns a copy of argument `n`, or of `default` if the
+  /// argument is absent or `nil`; raises an error if it is neither a string
+  /// nor a number.
+  pub fn opt_bytes(&mut self, n: Index, default: &[u8]) -> Vec<u8> {
+    if self.is_none_or_nil(n) {
+      default.to_vec()
+    } else {
+      self.check_bytes(n).to_vec()
+    }
+  }
+
+  /// Like `opt_string`, but replaces invalid UTF-8 in the argument with
+  /// U+FFFD instead of panicking.
+  pub fn opt_string_lossy<'a>(&'a mut self, n: Index, default: &'a str) -> Cow<'a, str> {
+    if self.is_none_or_nil(n) {
+      Cow::Borrowed(default)
+    } else {
+      self.check_string_lossy(n)
+    }
+  }
+
   // omitted: luaL_checkint (use .check_integer)
   // omitted: luaL_optint (use .opt_integer)
   // omitted: luaL_checklong (use .check_integer)
@@ -1643,13 +3989,121 @@ impl State {
   // TODO: omitted: buffer functions
 }
 
+/// Guard created by `State::stack_guard` that resets the top of the stack to
+/// the height recorded at creation when dropped.
+pub struct StackGuard<'a> {
+  state: &'a mut State,
+  top: Index,
+  armed: bool,
+}
+
+impl<'a> StackGuard<'a> {
+  /// Disarms the guard, leaving any values pushed since its creation on the
+  /// stack.
+  pub fn commit(mut self) {
+    self.armed = false;
+  }
+}
+
+impl<'a> Deref for StackGuard<'a> {
+  type Target = State;
+
+  fn deref(&self) -> &State {
+    self.state
+  }
+}
+
+impl<'a> DerefMut for StackGuard<'a> {
+  fn deref_mut(&mut self) -> &mut State {
+    self.state
+  }
+}
+
+impl<'a> Drop for StackGuard<'a> {
+  fn drop(&mut self) {
+    if self.armed {
+      self.state.set_top(self.top);
+    }
+  }
+}
+
+/// A thread on the stack of another state, returned by `State::thread_at`.
+/// It dereferences to the thread's `State` and cannot outlive the borrow of
+/// the parent.
+pub struct Thread<'a> {
+  thread: State,
+  _parent: PhantomData<&'a mut State>,
+}
+
+impl<'a> Deref for Thread<'a> {
+  type Target = State;
+
+  fn deref(&self) -> &State {
+    &self.thread
+  }
+}
+
+impl<'a> DerefMut for Thread<'a> {
+  fn deref_mut(&mut self) -> &mut State {
+    &mut self.thread
+  }
+}
+
+/// Builds a metatable in the registry for a userdata type, created through
+/// `State::metatable`. Nothing is changed until `finish` is called.
+pub struct MetatableBuilder<'a> {
+  state: &'a mut State,
+  name: String,
+  fields: Vec<(String, Function)>,
+}
+
+impl<'a> MetatableBuilder<'a> {
+  /// Adds a method, callable from Lua as `value:name(...)`.
+  pub fn method(mut self, name: &str, f: Function) -> MetatableBuilder<'a> {
+    self.fields.push((name.to_owned(), f));
+    self
+  }
+
+  /// Adds a metamethod such as `__gc`, `__tostring` or `__len`. The name must
+  /// include the leading underscores. Setting `__index` replaces the default
+  /// of looking up methods in the metatable itself.
+  pub fn meta(mut self, name: &str, f: Function) -> MetatableBuilder<'a> {
+    self.fields.push((name.to_owned(), f));
+    self
+  }
+
+  /// Creates the metatable with `new_metatable`, or updates it if a metatable
+  /// with this name already exists, and stores the methods and metamethods
+  /// in it. Unless a custom `__index` was given, `__index` is set to the
+  /// metatable itself so that methods can be found. The stack is left
+  /// unchanged. Returns `true` if the metatable was newly created.
+  pub fn finish(self) -> bool {
+    let created = self.state.new_metatable(&self.name);
+    self.state.push_value(-1);
+    self.state.set_field(-2, "__index");
+    for &(ref name, f) in &self.fields {
+      self.state.push_fn(f);
+      self.state.set_field(-2, name);
+    }
+    self.state.pop(1);
+    created
+  }
+}
+
 impl Drop for State {
   fn drop(&mut self) {
     if self.owned {
       unsafe {
-        let extra_ptr = ffi::lua_getextraspace(self.L) as ExtraHolder;
-        ptr::drop_in_place(*extra_ptr);
+        // `__gc` metamethods run by `lua_close` may still use the extra slot
+        let extra = *(ffi::lua_getextraspace(self.L) as ExtraHolder);
+        let stats = self.alloc_stats().map(|stats| stats as *mut AllocStats);
         ffi::lua_close(self.L);
+        if !extra.is_null() {
+          drop(Box::from_raw(extra));
+        }
+        if let Some(stats) = stats {
+          drop(Box::from_raw(stats));
+        }
       }
     }
   }
@@ -0,0 +1,82 @@
+extern crate lua;
+extern crate libc;
+
+use std::ptr;
+
+use libc::{c_void, size_t};
+use lua::{State, ThreadStatus};
+
+struct Counter {
+  allocations: usize,
+  bytes: usize,
+}
+
+unsafe extern "C" fn counting_alloc(ud: *mut c_void, ptr: *mut c_void, old_size: size_t, new_size: size_t) -> *mut c_void {
+  let counter = &mut *(ud as *mut Counter);
+  if new_size == 0 {
+    if !ptr.is_null() {
+      counter.bytes -= old_size as usize;
+    }
+    libc::free(ptr);
+    ptr::null_mut()
+  } else {
+    let new_ptr = libc::realloc(ptr, new_size);
+    if !new_ptr.is_null() {
+      counter.allocations += 1;
+      if !ptr.is_null() {
+        counter.bytes -= old_size as usize;
+      }
+      counter.bytes += new_size as usize;
+    }
+    new_ptr
+  }
+}
+
+#[test]
+fn test_with_allocator() {
+  let mut counter = Box::new(Counter { allocations: 0, bytes: 0 });
+  {
+    let ud = &mut *counter as *mut Counter as *mut c_void;
+    let mut state = unsafe { State::with_allocator(Some(counting_alloc), ud) }.unwrap();
+    let initial = counter.allocations;
+    assert!(initial > 0);
+
+    state.open_libs();
+    assert_eq!(state.do_string("t = {} for i = 1, 1000 do t[i] = tostring(i) end"), ThreadStatus::Ok);
+    assert!(counter.allocations > initial);
+    assert!(counter.bytes > 0);
+  }
+  assert_eq!(counter.bytes, 0);
+}
+
+#[test]
+fn test_new_counting() {
+  let mut state = State::new_counting();
+  state.open_libs();
+  let before = state.allocated_bytes().unwrap();
+  assert!(before > 0);
+
+  assert_eq!(state.do_string("big = {} for i = 1, 10000 do big[i] = i end"), ThreadStatus::Ok);
+  let during = state.allocated_bytes().unwrap();
+  assert!(during > before + 10000);
+
+  assert_eq!(state.do_string("big = nil collectgarbage()"), ThreadStatus::Ok);
+  assert!(state.allocated_bytes().unwrap() < during);
+  assert!(state.peak_bytes().unwrap() >= during);
+
+  assert_eq!(State::new().allocated_bytes(), None);
+}
+
+#[test]
+fn test_with_memory_limit() {
+  let mut state = State::with_memory_limit(1024 * 1024);
+  state.open_libs();
+
+  assert_eq!(state.load_string("local t = {} for i = 1, 1e7 do t[i] = i end"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::MemoryError);
+  state.pop(1);
+  assert!(state.peak_bytes().unwrap() <= 1024 * 1024);
+
+  // the state remains usable after the error
+  assert_eq!(state.do_string("small = {1, 2, 3}"), ThreadStatus::Ok);
+}
@@ -0,0 +1,46 @@
+extern crate lua;
+
+use lua::{State, Arithmetic, StackError};
+
+const OPS: [Arithmetic; 14] = [
+  Arithmetic::Add, Arithmetic::Sub, Arithmetic::Mul, Arithmetic::Mod,
+  Arithmetic::Pow, Arithmetic::Div, Arithmetic::IDiv, Arithmetic::BAnd,
+  Arithmetic::BOr, Arithmetic::BXor, Arithmetic::Shl, Arithmetic::Shr,
+  Arithmetic::Unm, Arithmetic::BNot,
+];
+
+#[test]
+fn test_arith_checked_empty_stack() {
+  let mut state = State::new();
+  for &op in OPS.iter() {
+    let expected = StackError::Underflow { required: op.operands(), available: 0 };
+    assert_eq!(state.arith_checked(op), Err(expected));
+    assert_eq!(state.get_top(), 0);
+  }
+}
+
+#[test]
+fn test_arith_checked_binary_with_one_operand() {
+  let mut state = State::new();
+  state.push_integer(1);
+  let result = state.arith_checked(Arithmetic::Add);
+  assert_eq!(result, Err(StackError::Underflow { required: 2, available: 1 }));
+  assert_eq!(state.get_top(), 1);
+}
+
+#[test]
+fn test_arith_checked() {
+  let mut state = State::new();
+  for &op in OPS.iter() {
+    for _ in 0..op.operands() {
+      state.push_integer(3);
+    }
+    assert_eq!(state.arith_checked(op), Ok(()));
+    assert_eq!(state.get_top(), 1);
+    state.pop(1);
+  }
+
+  state.push_integer(6);
+  assert_eq!(state.arith_checked(Arithmetic::Unm), Ok(()));
+  assert_eq!(state.to_integer(-1), -6);
+}
@@ -0,0 +1,201 @@
+extern crate lua;
+
+use lua::{State, Integer, Index, ThreadStatus, FromLua, LuaValue};
+
+#[test]
+fn test_pcall_traceback() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.do_string("
+    function inner() error('deep failure') end
+    function middle() inner() end
+    function outer() middle() end
+  "), ThreadStatus::Ok);
+
+  state.get_global("outer");
+  let err = state.pcall_traceback(0, 0).unwrap_err();
+  assert!(err.contains("deep failure"), "unexpected message: {}", err);
+  assert!(err.contains("stack traceback:"), "unexpected message: {}", err);
+  assert!(err.contains("inner"), "unexpected message: {}", err);
+  assert!(err.contains("middle"), "unexpected message: {}", err);
+  assert!(err.contains("outer"), "unexpected message: {}", err);
+  assert_eq!(state.get_top(), 0);
+}
+
+#[test]
+fn test_pcall_traceback_success() {
+  let mut state = State::new();
+  state.open_libs();
+  state.push_integer(1);
+  assert_eq!(state.do_string("function add(a, b) return a + b end"), ThreadStatus::Ok);
+
+  state.get_global("add");
+  state.push_integer(2);
+  state.push_integer(3);
+  assert_eq!(state.pcall_traceback(2, 1), Ok(()));
+  assert_eq!(state.get_top(), 2);
+  assert_eq!(state.to_integer(-1), 5);
+  assert_eq!(state.to_integer(-2), 1);
+}
+
+#[test]
+fn test_call_global() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.do_string("
+    function add(a, b) return a + b end
+    function concat3(a, b, c) return a .. b .. c end
+  "), ThreadStatus::Ok);
+
+  assert_eq!(state.call_global("add", (2 as Integer, 40 as Integer), 1), Ok(()));
+  assert_eq!(state.to_integer(-1), 42);
+  state.pop(1);
+
+  assert_eq!(state.call_global("concat3", (1 as Integer, "two", 3.5), 1), Ok(()));
+  assert_eq!(state.to_str_in_place(-1), Some("1two3.5"));
+  state.pop(1);
+
+  let err = state.call_global("add", ("x", true), 1).unwrap_err();
+  assert!(err.contains("attempt to perform arithmetic"), "unexpected message: {}", err);
+  assert!(state.call_global("missing", (), 0).is_err());
+  assert_eq!(state.get_top(), 0);
+}
+
+#[test]
+fn test_pcall_returns() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.do_string("
+    function pair() return 1, 'a' end
+    function single() return 1 end
+    function fail() error('failed', 0) end
+  "), ThreadStatus::Ok);
+
+  state.get_global("pair");
+  let (n, s): (Integer, String) = state.pcall_returns(0, 0).unwrap();
+  assert_eq!((n, s), (1, "a".to_owned()));
+
+  state.get_global("single");
+  let result: (Integer, Option<String>) = state.pcall_returns(0, 0).unwrap();
+  assert_eq!(result, (1, None));
+
+  state.get_global("single");
+  assert!(state.pcall_returns::<(Integer, Integer)>(0, 0).is_err());
+
+  state.get_global("fail");
+  assert_eq!(state.pcall_returns::<()>(0, 0), Err("failed".to_owned()));
+  assert_eq!(state.get_top(), 0);
+}
+
+struct ErrorCode(Integer);
+
+impl FromLua for ErrorCode {
+  fn from_lua(state: &mut State, index: Index) -> Option<ErrorCode> {
+    if !state.is_table(index) {
+      return None;
+    }
+    state.get_field(index, "code");
+    let code = state.to_type::<Integer>(-1);
+    state.pop(1);
+    code.map(ErrorCode)
+  }
+}
+
+#[test]
+fn test_take_error_value() {
+  let mut state = State::new();
+  state.open_base();
+
+  assert_eq!(state.load_string("error({code = 5})"), ThreadStatus::Ok);
+  let err = state.pcall_error_value::<ErrorCode>(0, 0, 0).unwrap_err();
+  assert_eq!(err.map(|e| e.0), Some(5));
+  assert_eq!(state.get_top(), 0);
+
+  assert_eq!(state.load_string("error('plain', 0)"), ThreadStatus::Ok);
+  assert!(state.pcall_error_value::<ErrorCode>(0, 0, 0).unwrap_err().is_none());
+  assert_eq!(state.get_top(), 0);
+
+  assert_eq!(state.load_string("error({code = 7})"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::RuntimeError);
+  match state.take_error_value::<LuaValue>() {
+    Some(LuaValue::Table(pairs)) => assert_eq!(pairs, vec![(LuaValue::Str(b"code".to_vec()), LuaValue::Integer(7))]),
+    other => panic!("unexpected error value {:?}", other),
+  }
+}
+
+#[test]
+fn test_is_callable() {
+  let mut state = State::new();
+  state.open_base();
+  assert_eq!(state.do_string("
+    f = function() end
+    callable = setmetatable({}, {__call = function() end})
+    plain = setmetatable({}, {__index = {}})
+  "), ThreadStatus::Ok);
+  state.get_global("f");
+  state.get_global("callable");
+  state.get_global("plain");
+  state.get_global("print");
+  state.push_integer(1);
+
+  assert!(state.is_callable(1));
+  assert!(state.is_callable(2));
+  assert!(!state.is_callable(3));
+  assert!(state.is_callable(4));
+  assert!(!state.is_callable(5));
+  assert_eq!(state.get_top(), 5);
+}
+
+#[test]
+fn test_pcall_index() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.load_string("local a, b = ...; return a + b, a * b"), ThreadStatus::Ok);
+  // the chunk is a local held at a known index; push something above it
+  state.push_string("above");
+  let (sum, product): (Integer, Integer) = state.pcall_index(1, (3 as Integer, 4 as Integer)).unwrap();
+  assert_eq!((sum, product), (7, 12));
+  let (sum,): (Integer,) = state.pcall_index(-2, (5 as Integer, 6 as Integer)).unwrap();
+  assert_eq!(sum, 11);
+  assert_eq!(state.get_top(), 2);
+  assert!(state.is_fn(1));
+
+  let err = state.pcall_index::<_, (Integer,)>(1, ("x", 1 as Integer)).unwrap_err();
+  assert!(err.contains("attempt to perform arithmetic"), "{}", err);
+  assert_eq!(state.get_top(), 2);
+}
+
+#[test]
+fn test_check_arg_count() {
+  let mut state = State::new();
+  state.open_libs();
+  state.push_closure_boxed(|state| {
+    state.check_arg_count(2);
+    let sum = state.check_integer(1) + state.check_integer(2);
+    state.push_integer(sum);
+    1
+  });
+  state.set_global("add");
+  state.push_closure_boxed(|state| {
+    state.check_arg_range(1, 2);
+    let n = state.get_top();
+    state.push_integer(n as Integer);
+    1
+  });
+  state.set_global("count");
+
+  let status = state.do_string("
+    assert(add(1, 2) == 3)
+    local ok, err = pcall(function() return add(1) end)
+    assert(not ok and err:find(\"bad argument #2 to 'add' %(expected 2 arguments, got 1%)\"), err)
+    ok, err = pcall(function() return add(1, 2, 3) end)
+    assert(not ok and err:find(\"bad argument #3 to 'add' %(expected 2 arguments, got 3%)\"), err)
+
+    assert(count(1) == 1 and count(1, 2) == 2)
+    ok, err = pcall(function() return count() end)
+    assert(not ok and err:find('expected 1 to 2 arguments, got 0', 1, true), err)
+    ok, err = pcall(function() return count(1, 2, 3) end)
+    assert(not ok and err:find('bad argument #3', 1, true), err)
+  ");
+  assert_eq!(status, ThreadStatus::Ok);
+}
@@ -0,0 +1,70 @@
+extern crate lua;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lua::{State, ThreadStatus};
+
+#[test]
+fn test_register_closure_counter() {
+  let mut state = State::new();
+
+  let counter = Arc::new(AtomicUsize::new(0));
+  let captured = counter.clone();
+  state.register_closure("increment", move |state| {
+    let n = captured.fetch_add(1, Ordering::SeqCst) + 1;
+    state.push_integer(n as lua::Integer);
+    1
+  });
+
+  let status = state.do_string("
+    increment()
+    increment()
+    result = increment()
+  ");
+  assert_eq!(status, ThreadStatus::Ok);
+  assert_eq!(counter.load(Ordering::SeqCst), 3);
+
+  state.get_global("result");
+  assert_eq!(state.to_integer(-1), 3);
+}
+
+#[test]
+fn test_push_closure_boxed_dropped() {
+  let counter = Arc::new(AtomicUsize::new(0));
+  {
+    let mut state = State::new();
+    let captured = counter.clone();
+    state.push_closure_boxed(move |_| {
+      captured.fetch_add(1, Ordering::SeqCst);
+      0
+    });
+    state.call(0, 0);
+    assert_eq!(Arc::strong_count(&counter), 2);
+  }
+  assert_eq!(counter.load(Ordering::SeqCst), 1);
+  assert_eq!(Arc::strong_count(&counter), 1);
+}
+
+#[test]
+fn test_push_iterator() {
+  let mut state = State::new();
+  state.open_libs();
+  state.push_iterator(0..3);
+  state.set_global("numbers");
+  state.push_iterator(vec!["a", "b"].into_iter());
+  state.set_global("letters");
+
+  let status = state.do_string("
+    collected = {}
+    for n in numbers do collected[#collected + 1] = n end
+    assert(#collected == 3)
+    assert(collected[1] == 0 and collected[2] == 1 and collected[3] == 2)
+    assert(numbers() == nil)
+
+    local s = ''
+    for l in letters do s = s .. l end
+    assert(s == 'ab')
+  ");
+  assert_eq!(status, ThreadStatus::Ok);
+}
@@ -0,0 +1,81 @@
+extern crate lua;
+
+use lua::{State, ThreadStatus};
+
+#[test]
+fn test_compare_numbers() {
+  let mut state = State::new();
+  state.push_integer(1);
+  state.push_number(2.5);
+  state.push_integer(1);
+
+  assert!(state.equals(1, 3));
+  assert!(!state.equals(1, 2));
+  assert!(state.less_than(1, 2));
+  assert!(!state.less_than(2, 1));
+  assert!(state.less_equal(1, 3));
+  assert!(!state.less_equal(2, 3));
+}
+
+#[test]
+fn test_compare_metamethods() {
+  let mut state = State::new();
+  state.open_base();
+  assert_eq!(state.do_string("
+    local mt = {
+      __lt = function(a, b) return a.v < b.v end,
+      __le = function(a, b) return a.v <= b.v end,
+      __eq = function(a, b) return a.v == b.v end,
+    }
+    a = setmetatable({v = 1}, mt)
+    b = setmetatable({v = 2}, mt)
+    c = setmetatable({v = 1}, mt)
+  "), ThreadStatus::Ok);
+  state.get_global("a");
+  state.get_global("b");
+  state.get_global("c");
+
+  assert!(state.less_than(1, 2));
+  assert!(!state.less_than(2, 1));
+  assert!(state.less_equal(1, 3));
+  assert!(state.equals(1, 3));
+  assert!(!state.raw_equal(1, 3));
+}
+
+#[test]
+fn test_deep_equal() {
+  let mut state = State::new();
+  assert_eq!(state.load_string("
+    return {1, 2, {x = 'a', y = {true}}}, {1, 2, {x = 'a', y = {true}}},
+           {1, 2, {x = 'a', y = {false}}}, {1, 2, {x = 'a'}}
+  "), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 4, 0), ThreadStatus::Ok);
+  assert!(!state.raw_equal(1, 2));
+  assert!(state.deep_equal(1, 2));
+  assert!(state.deep_equal(-4, -3));
+  assert!(!state.deep_equal(1, 3));
+  assert!(!state.deep_equal(1, 4));
+  assert!(!state.deep_equal(4, 1));
+  assert_eq!(state.get_top(), 4);
+
+  state.push_integer(1);
+  state.push_number(1.0);
+  assert!(state.deep_equal(-1, -2));
+  state.push_string("1");
+  assert!(!state.deep_equal(-1, -2));
+}
+
+#[test]
+fn test_deep_equal_cycles() {
+  let mut state = State::new();
+  assert_eq!(state.load_string("
+    local a = {name = 'node'}; a.next = a
+    local b = {name = 'node'}; b.next = b
+    local c = {name = 'node'}; c.next = {name = 'other', next = c}
+    return a, b, c
+  "), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 3, 0), ThreadStatus::Ok);
+  assert!(state.deep_equal(1, 2));
+  assert!(!state.deep_equal(1, 3));
+  assert_eq!(state.get_top(), 3);
+}
@@ -0,0 +1,242 @@
+extern crate lua;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lua::{State, Integer, Number, ThreadStatus, IntError, NumberKind, ToLuaMulti, Array};
+
+#[test]
+fn test_push_multi() {
+  let mut state = State::new();
+  assert_eq!(state.push_multi((1 as Integer, "two", 3.5)), 3);
+  assert_eq!(state.get_top(), 3);
+  assert_eq!(state.to_integer(1), 1);
+  assert_eq!(state.to_str_in_place(2), Some("two"));
+  assert_eq!(state.to_number(3), 3.5);
+
+  assert_eq!(state.push_multi(()), 0);
+  assert_eq!(state.get_top(), 3);
+}
+
+#[test]
+fn test_to_type_multi() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("function pair() return 7, 'seven' end"), ThreadStatus::Ok);
+  state.get_global("pair");
+  state.call(0, 2);
+
+  let pair: Option<(Integer, String)> = state.to_type_multi(-2);
+  assert_eq!(pair, Some((7, "seven".to_owned())));
+  let mismatch: Option<(Integer, Number)> = state.to_type_multi(-2);
+  assert_eq!(mismatch, None);
+  assert_eq!(state.get_top(), 2);
+}
+
+#[test]
+fn test_to_integer_checked() {
+  let mut state = State::new();
+  state.push_integer(42);
+  state.push_number(7.0);
+  state.push_number(3.5);
+  state.push_string("not a number");
+  state.push_string("12");
+
+  assert_eq!(state.to_integer_checked(1), Ok(42));
+  assert_eq!(state.to_integer_checked(2), Ok(7));
+  assert_eq!(state.to_integer_checked(3), Err(IntError::NoIntegerRepresentation));
+  assert_eq!(state.to_integer_checked(4), Err(IntError::NotANumber));
+  assert_eq!(state.to_integer_checked(5), Ok(12));
+}
+
+#[test]
+fn test_bytes_round_trip() {
+  let mut state = State::new();
+  let bytes = vec![0x4c, 0x00, 0xff, 0x61];
+
+  state.push(bytes.clone());
+  state.push(&bytes[1..]);
+  assert_eq!(state.raw_len(1), 4);
+  assert_eq!(state.to_type::<Vec<u8>>(1), Some(bytes.clone()));
+  assert_eq!(state.to_type::<Vec<u8>>(2), Some(bytes[1..].to_vec()));
+}
+
+#[test]
+fn test_to_vec() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("numbers = {1, 2, 3} mixed = {1, 'two', 3} empty = {}"), ThreadStatus::Ok);
+
+  state.get_global("numbers");
+  assert_eq!(state.to_vec::<Integer>(-1), Some(vec![1, 2, 3]));
+  state.get_global("mixed");
+  assert_eq!(state.to_vec::<Integer>(-1), None);
+  state.get_global("empty");
+  assert_eq!(state.to_vec::<Integer>(-1), Some(vec![]));
+  state.push_integer(1);
+  assert_eq!(state.to_vec::<Integer>(-1), None);
+  assert_eq!(state.get_top(), 4);
+}
+
+#[test]
+fn test_number_kind() {
+  let mut state = State::new();
+  state.push_integer(3);
+  state.push_number(3.0);
+  state.push_string("3");
+
+  assert_eq!(state.number_kind(1), Some(NumberKind::Integer));
+  assert_eq!(state.number_kind(2), Some(NumberKind::Float));
+  assert_eq!(state.number_kind(3), None);
+  assert_eq!(state.number_kind(4), None);
+}
+
+#[test]
+fn test_push_number_smart() {
+  let mut state = State::new();
+  state.push_number_smart(4.0);
+  state.push_number_smart(4.5);
+  state.push_number_smart(-9223372036854775808.0);
+  state.push_number_smart(9223372036854775808.0);
+  state.push_number_smart(std::f64::NAN);
+
+  assert!(state.is_integer(1));
+  assert_eq!(state.to_integer(1), 4);
+  assert!(!state.is_integer(2));
+  assert!(state.is_integer(3));
+  assert_eq!(state.to_integer(3), std::i64::MIN);
+  assert!(!state.is_integer(4));
+  assert!(!state.is_integer(5));
+}
+
+#[test]
+fn test_time_round_trip() {
+  let mut state = State::new();
+  state.push(Duration::from_millis(1500));
+  assert_eq!(state.to_number(-1), 1.5);
+  assert_eq!(state.to_type::<Duration>(-1), Some(Duration::from_millis(1500)));
+
+  let time = UNIX_EPOCH + Duration::from_secs(1000000000);
+  state.push(time);
+  assert_eq!(state.to_number(-1), 1000000000.0);
+  assert_eq!(state.to_type::<SystemTime>(-1), Some(time));
+
+  let before = UNIX_EPOCH - Duration::from_secs(60);
+  state.push(before);
+  assert_eq!(state.to_number(-1), -60.0);
+  assert_eq!(state.to_type::<SystemTime>(-1), Some(before));
+
+  state.push_number(-1.0);
+  assert_eq!(state.to_type::<Duration>(-1), None);
+}
+
+#[test]
+fn test_push_result() {
+  let mut state = State::new();
+  state.open_base();
+  state.push_closure_boxed(|state| {
+    let n = state.check_integer(1);
+    let result: Result<Integer, String> = if n >= 0 {
+      Ok(n * 2)
+    } else {
+      Err(format!("negative input {}", n))
+    };
+    state.push_multi(result)
+  });
+  state.set_global("double");
+
+  assert_eq!(state.do_string("
+    local v, err = double(21)
+    assert(v == 42 and err == nil)
+    local v, err = double(-1)
+    assert(v == nil and err == 'negative input -1')
+    assert(select('#', double(-1)) == 2)
+  "), ThreadStatus::Ok);
+}
+
+#[test]
+fn test_push_multi_counts() {
+  let mut state = State::new();
+  assert_eq!(42.push_multi(&mut state), 1);
+  assert_eq!((1 as Integer, "two").push_multi(&mut state), 2);
+  let err: Result<Integer, &str> = Err("failed");
+  assert_eq!(err.push_multi(&mut state), 2);
+  assert_eq!(state.get_top(), 5);
+  assert!(state.is_nil(4));
+  assert_eq!(state.to_str_in_place(5), Some("failed"));
+
+  state.push_closure_boxed(|state| "from closure".push_multi(state));
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+  assert_eq!(state.to_str_in_place(-1), Some("from closure"));
+}
+
+#[test]
+fn test_char_round_trip() {
+  let mut state = State::new();
+  state.push('a');
+  assert_eq!(state.to_str_in_place(-1), Some("a"));
+  assert_eq!(state.to_type::<char>(-1), Some('a'));
+
+  state.push('\u{20ac}');
+  assert_eq!(state.raw_len(-1), 3);
+  assert_eq!(state.to_type::<char>(-1), Some('\u{20ac}'));
+
+  state.push_string("ab");
+  assert_eq!(state.to_type::<char>(-1), None);
+  state.push_string("");
+  assert_eq!(state.to_type::<char>(-1), None);
+  state.push_integer(5);
+  assert_eq!(state.to_type::<char>(-1), None);
+  assert!(state.is_integer(-1));
+}
+
+#[test]
+fn test_array_round_trip() {
+  let mut state = State::new();
+  state.open_libs();
+  state.push(Array((1 as Integer, "x".to_string(), true)));
+  assert_eq!(state.get_top(), 1);
+  assert_eq!(state.raw_len(-1), 3);
+  state.set_global("t");
+  assert_eq!(state.do_string("
+    assert(t[1] == 1 and t[2] == 'x' and t[3] == true)
+    t[4] = 'ignored'
+  "), ThreadStatus::Ok);
+
+  state.get_global("t");
+  let Array(tuple) = state.to_type::<Array<(Integer, String, bool)>>(-1).unwrap();
+  assert_eq!(tuple, (1, "x".to_string(), true));
+  assert_eq!(state.to_type::<Array<(Integer, bool)>>(-1), None);
+  assert_eq!(state.to_type::<Array<(Integer, String, bool, Option<bool>)>>(-1), None);
+
+  // a plain tuple still pushes separate values
+  assert_eq!((1 as Integer, 2 as Integer).push_multi(&mut state), 2);
+  assert_eq!(state.to_type::<Array<(Integer,)>>(-1), None);
+}
+
+#[test]
+fn test_string_from_any_value() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.do_string("
+    t = {[10] = 'ten', [2.5] = 'float'}
+    named = setmetatable({}, {__tostring = function() return 'named' end})
+  "), ThreadStatus::Ok);
+
+  state.push_nil();
+  state.push_bool(true);
+  state.get_global("named");
+  assert_eq!(state.to_type::<String>(1), Some("nil".to_owned()));
+  assert_eq!(state.to_type::<String>(2), Some("true".to_owned()));
+  assert_eq!(state.to_type::<String>(3), Some("named".to_owned()));
+  assert_eq!(state.get_top(), 3);
+  state.set_top(0);
+
+  // converting number keys must not disturb the traversal
+  state.get_global("t");
+  let mut keys = Vec::new();
+  state.for_each_pair(-1, |state| {
+    keys.push(state.to_type::<String>(-2).unwrap());
+    assert_eq!(state.type_of(-2), Some(lua::Type::Number));
+  });
+  keys.sort();
+  assert_eq!(keys, vec!["10".to_owned(), "2.5".to_owned()]);
+  assert_eq!(state.get_top(), 1);
+}
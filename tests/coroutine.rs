@@ -0,0 +1,172 @@
+extern crate lua;
+extern crate libc;
+
+use lua::ffi::lua_State;
+use lua::{State, ThreadStatus, Coroutine, CoResult, LuaValue, Integer, YieldError};
+use libc::c_int;
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn yield_then_continue(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  state.push_integer(1);
+  state.co_yieldk(1, |state, status| {
+    assert_eq!(status, ThreadStatus::Yield);
+    state.push_integer(42);
+    1
+  })
+}
+
+#[test]
+fn test_co_yieldk_continuation() {
+  let mut state = State::new();
+  state.open_libs();
+  state.register("yield_then_continue", Some(yield_then_continue));
+
+  let status = state.do_string("
+    local co = coroutine.create(function() return yield_then_continue() end)
+    local ok1, a = coroutine.resume(co)
+    local ok2, b = coroutine.resume(co)
+    assert(ok1 and a == 1)
+    assert(ok2 and b == 42)
+    assert(coroutine.status(co) == 'dead')
+  ");
+  assert_eq!(status, ThreadStatus::Ok);
+}
+
+#[test]
+fn test_co_yield_from_thread() {
+  let mut state = State::new();
+  state.open_libs();
+  let mut thread = state.new_thread();
+
+  thread.get_global("coroutine");
+  thread.get_field(-1, "yield");
+  thread.remove(-2);
+  thread.push_integer(7);
+  assert_eq!(thread.resume(None, 1), ThreadStatus::Yield);
+  assert_eq!(thread.to_integer(-1), 7);
+}
+
+#[test]
+fn test_coroutine_generator() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.do_string("
+    function range(n)
+      for i = 1, n do
+        local reply = coroutine.yield(i)
+        assert(reply == i * 10)
+      end
+      return 'done'
+    end
+  "), ThreadStatus::Ok);
+  state.get_global("range");
+  let mut co = Coroutine::new(&mut state);
+
+  assert_eq!(co.resume_with(3 as Integer), CoResult::Yielded(vec![LuaValue::Integer(1)]));
+  let mut seen = vec![1];
+  loop {
+    let reply = *seen.last().unwrap() * 10;
+    match co.resume_with(reply) {
+      CoResult::Yielded(values) => match values[0] {
+        LuaValue::Integer(i) => seen.push(i),
+        ref other => panic!("unexpected value {:?}", other),
+      },
+      CoResult::Returned(values) => {
+        assert_eq!(values, vec![LuaValue::Str(b"done".to_vec())]);
+        break;
+      }
+      CoResult::Error(msg) => panic!("coroutine failed: {}", msg),
+    }
+  }
+  assert_eq!(seen, vec![1, 2, 3]);
+
+  match co.resume_with(()) {
+    CoResult::Error(msg) => assert_eq!(msg, "cannot resume dead coroutine"),
+    other => panic!("unexpected result {:?}", other),
+  }
+
+  assert!(co.is_dead());
+  drop(co);
+  assert_eq!(state.get_top(), 0);
+  state.gc_collect();
+}
+
+#[test]
+fn test_coroutine_error() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.load_string("error('inside', 0)"), ThreadStatus::Ok);
+  let mut co = Coroutine::new(&mut state);
+  assert_eq!(co.resume_with(()), CoResult::Error("inside".to_owned()));
+}
+
+#[test]
+fn test_coroutine_error_object_with_tostring() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.load_string("
+    error(setmetatable({}, { __tostring = function() return 'custom' end }))
+  "), ThreadStatus::Ok);
+  let mut co = Coroutine::new(&mut state);
+  assert_eq!(co.resume_with(()), CoResult::Error("(error object is a table value)".to_owned()));
+  assert!(co.is_dead());
+}
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn try_yield_one(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  state.push_integer(7);
+  match state.try_yield(1) {
+    Ok(n) => n,
+    Err(_) => {
+      state.push_string("not yieldable");
+      2
+    }
+  }
+}
+
+#[test]
+fn test_try_yield() {
+  let mut state = State::new();
+  state.open_libs();
+  state.push_integer(1);
+  assert_eq!(state.try_yield(1), Err(YieldError::NotYieldable));
+  assert_eq!(state.get_top(), 1);
+
+  state.register("try_yield_one", Some(try_yield_one));
+  let status = state.do_string("
+    local a, b = try_yield_one()
+    assert(a == 7 and b == 'not yieldable')
+    local co = coroutine.wrap(function() return 'after', try_yield_one() end)
+    assert(co() == 7)
+    assert(co('resumed') == 'after')
+  ");
+  assert_eq!(status, ThreadStatus::Ok);
+}
+
+#[test]
+fn test_thread_at() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.load_string("return coroutine.create(function(a) local b = coroutine.yield(a + 1) return b * 2 end)"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+  state.push_integer(3);
+  assert!(state.thread_at(-1).is_none());
+  state.pop(1);
+
+  {
+    let mut thread = state.thread_at(-1).unwrap();
+    thread.push_integer(1);
+    assert_eq!(thread.resume(None, 1), ThreadStatus::Yield);
+    assert_eq!(thread.to_integer(-1), 2);
+    thread.pop(1);
+    thread.push_integer(5);
+    assert_eq!(thread.resume(None, 1), ThreadStatus::Ok);
+    assert_eq!(thread.to_integer(-1), 10);
+  }
+
+  // the parent can be used again once the handle is gone
+  state.pop(1);
+  assert_eq!(state.get_top(), 0);
+}
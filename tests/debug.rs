@@ -0,0 +1,144 @@
+extern crate lua;
+
+use std::sync::{Arc, Mutex};
+
+use lua::{State, ThreadStatus, DebugInfo};
+
+#[test]
+fn test_get_info_for_function() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("x = 1\n\nfunction f()\n  return 1\nend"), ThreadStatus::Ok);
+
+  state.get_global("f");
+  let info = state.get_info_for_function("Sn");
+  assert_eq!(state.get_top(), 0);
+  assert_eq!(info.line_defined(), 3);
+  assert_eq!(info.what(), Some("Lua"));
+  assert_eq!(info.source(), Some("x = 1\n\nfunction f()\n  return 1\nend"));
+  assert_eq!(info.name(), None);
+}
+
+#[test]
+#[should_panic]
+fn test_get_info_for_function_not_a_function() {
+  let mut state = State::new();
+  state.push(1);
+  state.get_info_for_function("S");
+}
+
+#[test]
+#[should_panic]
+fn test_get_info_for_function_rejects_gt() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("function f() end"), ThreadStatus::Ok);
+  state.get_global("f");
+  state.get_info_for_function(">S");
+}
+
+#[test]
+#[should_panic]
+fn test_get_info_for_function_rejects_pushing_options() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("function f() end"), ThreadStatus::Ok);
+  state.get_global("f");
+  state.get_info_for_function("SL");
+}
+
+#[test]
+fn test_get_stack_info() {
+  let mut state = State::new();
+  state.open_libs();
+  state.push_closure_boxed(|state| {
+    let info = state.get_stack_info(1, "Sl").unwrap();
+    let line = info.current_line() as lua::Integer;
+    let source = info.source().map(|s| s.to_owned());
+    assert!(state.get_stack_info(10, "Sl").is_none());
+    state.push_string(&source.unwrap());
+    state.push_integer(line);
+    2
+  });
+  state.set_global("where");
+
+  assert_eq!(state.load_bufferx(b"\n\nsrc, line = where()", "=frames", "t"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::Ok);
+  state.get_global("src");
+  assert_eq!(state.to_str_in_place(-1), Some("=frames"));
+  state.get_global("line");
+  assert_eq!(state.to_integer(-1), 3);
+}
+
+#[test]
+fn test_traceback_string() {
+  let mut state = State::new();
+  state.push_closure_boxed(|state| {
+    let mut thread = unsafe { State::from_ptr(state.as_ptr()) };
+    let trace = state.traceback_string(&mut thread, "captured", 1);
+    state.push_string(&trace);
+    1
+  });
+  state.set_global("capture");
+
+  assert_eq!(state.load_bufferx(b"
+    local function c() local t = capture() return t end
+    local function b() local t = c() return t end
+    local function a() local t = b() return t end
+    trace = a()
+  ", "=nested", "t"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::Ok);
+  assert_eq!(state.get_top(), 0);
+
+  state.get_global("trace");
+  let trace = state.to_str_in_place(-1).unwrap().to_owned();
+  assert!(trace.starts_with("captured\nstack traceback:"), "{}", trace);
+  let frames: Vec<&str> = trace.lines().skip(2).collect();
+  assert_eq!(frames.len(), 4, "{}", trace);
+  assert!(frames[0].contains("nested:2:"), "{}", trace);
+  assert!(frames[3].contains("main chunk"), "{}", trace);
+}
+
+#[test]
+fn test_where_string() {
+  let mut state = State::new();
+  state.push_closure_boxed(|state| {
+    let location = state.where_string(1);
+    state.push_string(&location);
+    1
+  });
+  state.set_global("here");
+  state.push_closure_boxed(|state| state.raise_error_at(1, "bad input"));
+  state.set_global("fail");
+
+  assert_eq!(state.load_bufferx(b"\nlocation = here()\nfail()", "=located", "t"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::RuntimeError);
+  assert_eq!(state.to_str_in_place(-1), Some("located:3: bad input"));
+  state.get_global("location");
+  assert_eq!(state.to_str_in_place(-1), Some("located:2: "));
+}
+
+#[test]
+fn test_collect_traceback() {
+  let mut state = State::new();
+  assert_eq!(state.load_bufferx(b"
+    function fail_here() local x = nil; return x + 1 end
+    function caller() local r = fail_here() return r end
+  ", "=frames", "t"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::Ok);
+
+  let frames: Arc<Mutex<Vec<DebugInfo>>> = Arc::new(Mutex::new(Vec::new()));
+  let captured = frames.clone();
+  state.push_closure_boxed(move |state| {
+    // level 0 is this handler; level 1 raised the error
+    *captured.lock().unwrap() = state.collect_traceback(1);
+    1
+  });
+  state.get_global("caller");
+  assert!(state.pcall(0, 0, 1).is_err());
+
+  let frames = frames.lock().unwrap();
+  assert_eq!(frames[0].name(), Some("fail_here"));
+  assert_eq!(frames[0].current_line(), 2);
+  assert_eq!(frames[0].source(), Some("=frames"));
+  // called directly from Rust, so Lua cannot name it
+  assert_eq!(frames[1].name(), None);
+  assert_eq!(frames[1].current_line(), 3);
+}
@@ -1,4 +1,11 @@
 extern crate lua;
+extern crate libc;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use lua::ffi::lua_State;
+use libc::c_int;
 
 #[derive(PartialEq)]
 struct Data {
@@ -75,3 +82,101 @@ fn test_extra_threads() {
   });
   assert_eq!(value, "Thread data");
 }
+
+struct Config {
+  name: &'static str,
+}
+
+struct Counter(u32);
+
+#[test]
+fn test_app_data() {
+  let mut state = lua::State::new();
+  assert!(state.app_data::<Config>().is_none());
+
+  assert!(state.set_app_data(Config { name: "app" }).is_none());
+  assert!(state.set_app_data(Counter(1)).is_none());
+  state.app_data::<Counter>().unwrap().0 += 1;
+
+  let mut thread = state.new_thread();
+  assert_eq!(thread.app_data::<Config>().map(|c| c.name), Some("app"));
+  assert_eq!(thread.app_data::<Counter>().map(|c| c.0), Some(2));
+
+  let previous = state.set_app_data(Counter(10)).unwrap();
+  assert_eq!(previous.0, 2);
+  assert_eq!(state.remove_app_data::<Counter>().map(|c| c.0), Some(10));
+  assert!(state.app_data::<Counter>().is_none());
+  assert_eq!(state.app_data::<Config>().map(|c| c.name), Some("app"));
+  assert_eq!(state.get_top(), 1);
+}
+
+struct DropFlag(Arc<AtomicBool>);
+
+impl Drop for DropFlag {
+  fn drop(&mut self) {
+    self.0.store(true, Ordering::SeqCst);
+  }
+}
+
+#[test]
+fn test_extra_dropped_with_state() {
+  let dropped = Arc::new(AtomicBool::new(false));
+  let mut state = lua::State::new();
+  state.set_extra(Some(Box::new(DropFlag(dropped.clone()))));
+  {
+    let _thread = state.new_thread();
+  }
+  assert!(!dropped.load(Ordering::SeqCst));
+  drop(state);
+  assert!(dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_extra_coroutines() {
+  let mut state = lua::State::new();
+  state.open_libs();
+  state.set_extra(Some(Box::new(Data { value: "shared".to_owned() })));
+  state.push_closure_boxed(|state| {
+    let value = state.with_extra_typed(|data: &mut Data| data.value.clone());
+    state.push_string(&value);
+    1
+  });
+  state.set_global("read_extra");
+
+  assert_eq!(state.do_string("
+    local co = coroutine.wrap(function() coroutine.yield(read_extra()) end)
+    result = co()
+  "), lua::ThreadStatus::Ok);
+  state.get_global("result");
+  assert_eq!(state.to_str_in_place(-1), Some("shared"));
+
+  // dropping a thread wrapper must not release the shared slot
+  drop(state.new_thread());
+  let value = state.with_extra_typed(|data: &mut Data| data.value.clone());
+  assert_eq!(value, "shared");
+}
+
+static GC_SAW_EXTRA: AtomicBool = AtomicBool::new(false);
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn read_extra_gc(L: *mut lua_State) -> c_int {
+  let mut state = lua::State::from_ptr(L);
+  let value = state.with_extra_typed(|data: &mut Data| data.value.clone());
+  GC_SAW_EXTRA.store(value == "kept", Ordering::SeqCst);
+  0
+}
+
+#[test]
+fn test_extra_available_during_close() {
+  let mut state = lua::State::new();
+  state.set_extra(Some(Box::new(Data { value: "kept".to_owned() })));
+  state.new_table();
+  state.new_table();
+  state.push_fn(Some(read_extra_gc));
+  state.set_field(-2, "__gc");
+  state.set_metatable(-2);
+  state.set_global("finalized_on_close");
+
+  drop(state);
+  assert!(GC_SAW_EXTRA.load(Ordering::SeqCst));
+}
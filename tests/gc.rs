@@ -0,0 +1,68 @@
+extern crate lua;
+
+use lua::{State, ThreadStatus, Type, WeakMode};
+
+#[test]
+fn test_gc_count() {
+  let mut state = State::new();
+  state.open_libs();
+  state.gc_collect();
+  let before_kb = state.gc_count_kb();
+  let before_bytes = state.gc_count_bytes();
+
+  assert_eq!(state.do_string("
+    big = {}
+    for i = 1, 100000 do big[i] = i end
+  "), ThreadStatus::Ok);
+  assert!(state.gc_count_kb() > before_kb);
+  assert!(state.gc_count_bytes() > before_bytes);
+
+  assert_eq!(state.do_string("big = nil"), ThreadStatus::Ok);
+  state.gc_collect();
+  assert!(state.gc_count_kb() < before_kb + 100);
+}
+
+#[test]
+fn test_gc_running() {
+  let mut state = State::new();
+  assert!(state.gc_is_running());
+  state.gc_stop();
+  assert!(!state.gc_is_running());
+  state.gc_restart();
+  assert!(state.gc_is_running());
+
+  let pause = state.gc_set_pause(150);
+  assert_eq!(state.gc_set_pause(pause), 150);
+  let mul = state.gc_set_step_mul(300);
+  assert_eq!(state.gc_set_step_mul(mul), 300);
+}
+
+#[test]
+fn test_create_weak_table() {
+  let mut state = State::new();
+  state.create_weak_table(WeakMode::Values);
+  state.new_table();
+  state.set_field(-2, "collected");
+  state.push_string("kept");
+  state.set_field(-2, "strong");
+
+  state.create_weak_table(WeakMode::Keys);
+  state.new_table();
+  state.push_value(-1);
+  state.push_bool(true);
+  state.set_table(-4);
+  // keep the key alive through the stack
+  state.gc_collect();
+  assert_eq!(state.keys(-2).len(), 1);
+  state.pop(1);
+
+  state.gc_collect();
+  assert_eq!(state.get_field(1, "collected"), Type::Nil);
+  assert_eq!(state.get_field(1, "strong"), Type::String);
+  state.pop(2);
+  assert!(state.keys(2).is_empty());
+
+  state.create_weak_table(WeakMode::Both);
+  assert!(state.get_metafield(-1, "__mode"));
+  assert_eq!(state.to_str_in_place(-1), Some("kv"));
+}
@@ -0,0 +1,139 @@
+extern crate lua;
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use lua::{State, ThreadStatus, MASKLINE};
+
+#[test]
+fn test_limit_instructions() {
+  let mut state = State::new();
+  state.open_libs();
+  state.limit_instructions(100000);
+
+  assert_eq!(state.load_string("while true do end"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::RuntimeError);
+  assert_eq!(state.to_str_in_place(-1), Some("instruction limit exceeded"));
+  state.pop(1);
+
+  state.limit_instructions(100000);
+  assert_eq!(state.do_string("local x = 0 for i = 1, 100 do x = x + i end"), ThreadStatus::Ok);
+}
+
+#[test]
+fn test_limit_instructions_in_coroutine() {
+  let mut state = State::new();
+  state.open_libs();
+  state.limit_instructions(100000);
+
+  let status = state.do_string("coroutine.wrap(function() for i = 1, 5e7 do end end)()");
+  assert_eq!(status, ThreadStatus::RuntimeError);
+  let msg = state.to_string_meta(-1);
+  assert!(msg.contains("instruction limit exceeded"), "{}", msg);
+}
+
+#[test]
+fn test_set_deadline() {
+  let mut state = State::new();
+  state.open_libs();
+
+  let start = Instant::now();
+  state.set_deadline(Duration::from_millis(50));
+  assert_eq!(state.load_string("while true do end"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::RuntimeError);
+  let elapsed = start.elapsed();
+  assert_eq!(state.to_str_in_place(-1), Some("execution deadline exceeded"));
+
+  assert!(elapsed >= Duration::from_millis(50));
+  assert!(elapsed < Duration::from_millis(1000), "took {:?}", elapsed);
+}
+
+#[test]
+fn test_set_deadline_in_coroutine() {
+  let mut state = State::new();
+  state.open_libs();
+
+  let start = Instant::now();
+  state.set_deadline(Duration::from_millis(50));
+  let status = state.do_string("coroutine.wrap(function() while true do end end)()");
+  let elapsed = start.elapsed();
+  assert_eq!(status, ThreadStatus::RuntimeError);
+  let msg = state.to_string_meta(-1);
+  assert!(msg.contains("execution deadline exceeded"), "{}", msg);
+  assert!(elapsed < Duration::from_millis(1000), "took {:?}", elapsed);
+}
+
+#[test]
+fn test_set_hook_fn() {
+  let mut state = State::new();
+  let lines = Arc::new(AtomicUsize::new(0));
+  let counter = lines.clone();
+  state.set_hook_fn(MASKLINE, 0, move |_, _| { counter.fetch_add(1, Ordering::SeqCst); });
+
+  assert_eq!(state.do_string("local a = 1\nlocal b = 2\nlocal c = 3"), ThreadStatus::Ok);
+  assert_eq!(lines.load(Ordering::SeqCst), 3);
+
+  state.remove_hook();
+  assert_eq!(state.do_string("local a = 1"), ThreadStatus::Ok);
+  assert_eq!(lines.load(Ordering::SeqCst), 3);
+
+  state.gc_collect();
+  assert_eq!(Arc::strong_count(&lines), 1);
+}
+
+#[test]
+fn test_stack_frame_info_in_hook() {
+  let mut state = State::new();
+  let frames = Arc::new(Mutex::new(Vec::new()));
+  let seen = frames.clone();
+  state.set_hook_fn(MASKLINE, 0, move |state, _| {
+    let info = state.stack_frame_info(0, "").unwrap();
+    seen.lock().unwrap().push((info.source().map(|s| s.to_owned()), info.current_line()));
+  });
+
+  assert_eq!(state.load_bufferx(b"local a = 1\n\nlocal b = 2", "=lines", "t"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::Ok);
+  state.remove_hook();
+  let source = Some("=lines".to_owned());
+  assert_eq!(*frames.lock().unwrap(), vec![(source.clone(), 1), (source, 3)]);
+}
+
+#[test]
+fn test_set_hook_fn_replaced() {
+  let mut state = State::new();
+  let first = Arc::new(AtomicUsize::new(0));
+  let second = Arc::new(AtomicUsize::new(0));
+  let counter = first.clone();
+  state.set_hook_fn(MASKLINE, 0, move |_, _| { counter.fetch_add(1, Ordering::SeqCst); });
+  let counter = second.clone();
+  state.set_hook_fn(MASKLINE, 0, move |_, _| { counter.fetch_add(1, Ordering::SeqCst); });
+
+  // the replaced closure is dropped by the next collection
+  state.gc_collect();
+  assert_eq!(Arc::strong_count(&first), 1);
+  assert_eq!(state.do_string("local a = 1"), ThreadStatus::Ok);
+  assert_eq!(first.load(Ordering::SeqCst), 0);
+  assert_eq!(second.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_set_hook_fn_shared_by_threads() {
+  let mut state = State::new();
+  state.open_libs();
+  let lines = Arc::new(AtomicUsize::new(0));
+  let counter = lines.clone();
+  state.set_hook_fn(MASKLINE, 0, move |_, _| { counter.fetch_add(1, Ordering::SeqCst); });
+
+  assert_eq!(state.load_bufferx(b"local co = coroutine.wrap(function()\n  local a = 1\n  local b = 2\nend)\nco()", "=co", "t"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::Ok);
+  // lines 1 and 4 run on the main thread, lines 2 and 3 in the coroutine
+  assert!(lines.load(Ordering::SeqCst) >= 4, "{}", lines.load(Ordering::SeqCst));
+
+  let mut thread = state.new_thread();
+  state.remove_hook();
+  let before = lines.load(Ordering::SeqCst);
+  assert_eq!(thread.load_string("local a = 1"), ThreadStatus::Ok);
+  assert_eq!(thread.resume(None, 0), ThreadStatus::Ok);
+  assert_eq!(lines.load(Ordering::SeqCst), before);
+}
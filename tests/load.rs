@@ -0,0 +1,206 @@
+#[macro_use]
+extern crate lua;
+
+use std::{env, fs, process};
+use std::io::{self, Cursor, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use lua::{State, ThreadStatus};
+use lua::libc::c_int;
+
+#[test]
+fn test_compile_chunk_name() {
+  let mut state = State::new();
+  state.open_libs();
+
+  assert_eq!(state.compile("local x = 1\nerror('boom')", "=mychunk"), Ok(()));
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::RuntimeError);
+  assert_eq!(state.to_str_in_place(-1), Some("mychunk:2: boom"));
+}
+
+#[test]
+fn test_compile_syntax_error() {
+  let mut state = State::new();
+
+  let err = state.compile("local = 1", "=broken").unwrap_err();
+  assert!(err.starts_with("broken:1:"), "unexpected message: {}", err);
+  assert_eq!(state.get_top(), 0);
+}
+
+#[test]
+fn test_load_reader() {
+  let mut state = State::new();
+  let source = b"local t = {} for i = 1, 3 do t[i] = i * 2 end return t[3]".to_vec();
+
+  assert_eq!(state.load_reader(Cursor::new(source), "=cursor", "t"), Ok(()));
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+  assert_eq!(state.to_integer(-1), 6);
+}
+
+struct FailingReader;
+
+impl Read for FailingReader {
+  fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+    Err(io::Error::new(io::ErrorKind::Other, "disk on fire"))
+  }
+}
+
+#[test]
+fn test_load_reader_error() {
+  let mut state = State::new();
+  let reader = Cursor::new(b"return 1\n".to_vec()).chain(FailingReader);
+
+  let err = state.load_reader(reader, "=failing", "t").unwrap_err();
+  assert_eq!(err, "cannot read =failing: disk on fire");
+  assert_eq!(state.get_top(), 0);
+}
+
+#[test]
+fn test_dump_writer() {
+  let mut state = State::new();
+  assert_eq!(state.compile("return 40 + 2", "=dumped"), Ok(()));
+
+  let mut bytes = Vec::new();
+  assert!(state.dump_writer(&mut bytes, false).is_ok());
+  assert_eq!(state.get_top(), 1);
+  state.pop(1);
+
+  assert_eq!(state.load_reader(Cursor::new(bytes), "=dumped", "b"), Ok(()));
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+  assert_eq!(state.to_integer(-1), 42);
+}
+
+#[test]
+fn test_dump_writer_error() {
+  let mut state = State::new();
+  assert_eq!(state.compile("return 1", "=dumped"), Ok(()));
+
+  let mut buf = [0u8; 4];
+  let err = state.dump_writer(&mut buf[..], false).unwrap_err();
+  assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+}
+
+fn write_script(name: &str, contents: &str) -> PathBuf {
+  let path = env::temp_dir().join(format!("rust-lua53-{}-{}.lua", process::id(), name));
+  fs::File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+  path
+}
+
+#[test]
+fn test_run_file() {
+  let mut state = State::new();
+  let path = write_script("run-ok", "answer = 42");
+
+  assert_eq!(state.run_file(&path), Ok(()));
+  fs::remove_file(&path).unwrap();
+  state.get_global("answer");
+  assert_eq!(state.to_integer(-1), 42);
+}
+
+#[test]
+fn test_run_file_missing() {
+  let mut state = State::new();
+  let err = state.run_file("/nonexistent/script.lua").unwrap_err();
+  assert!(err.starts_with("file error: cannot open"), "unexpected message: {}", err);
+  assert_eq!(state.get_top(), 0);
+}
+
+#[test]
+fn test_run_file_errors() {
+  let mut state = State::new();
+  state.open_libs();
+
+  let path = write_script("run-syntax", "local = 1");
+  let err = state.run_file(&path).unwrap_err();
+  fs::remove_file(&path).unwrap();
+  assert!(err.starts_with("syntax error: "), "unexpected message: {}", err);
+
+  let path = write_script("run-runtime", "error('boom', 0)");
+  let err = state.run_file(&path).unwrap_err();
+  fs::remove_file(&path).unwrap();
+  assert_eq!(err, "runtime error: boom");
+  assert_eq!(state.get_top(), 0);
+}
+
+#[test]
+fn test_load_file_path_buf() {
+  let mut state = State::new();
+  let path = write_script("load-path", "return 'from a PathBuf'");
+
+  assert_eq!(state.load_file(&path), ThreadStatus::Ok);
+  fs::remove_file(&path).unwrap();
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+  assert_eq!(state.to_str_in_place(-1), Some("from a PathBuf"));
+}
+
+#[test]
+fn test_load_chunks() {
+  let mut state = State::new();
+  let chunks = vec![b"return 'split ".to_vec(), Vec::new(), b"across pieces'".to_vec()];
+
+  assert_eq!(state.load_chunks(chunks.into_iter(), "=chunks", "t"), Ok(()));
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+  assert_eq!(state.to_str_in_place(-1), Some("split across pieces"));
+  state.pop(1);
+
+  let err = state.load_chunks(vec![b"return (".to_vec()].into_iter(), "=chunks", "t").unwrap_err();
+  assert!(err.starts_with("chunks:1:"), "unexpected message: {}", err);
+  assert_eq!(state.get_top(), 0);
+}
+
+#[test]
+fn test_set_chunk_env() {
+  let mut state = State::new();
+  state.open_base();
+  state.push_string("real");
+  state.set_global("secret");
+
+  let printed = Arc::new(Mutex::new(Vec::new()));
+  let sink = printed.clone();
+  state.new_table();
+  state.push_closure_boxed(move |state| {
+    let line = state.to_string_meta(1);
+    sink.lock().unwrap().push(line);
+    0
+  });
+  state.set_field(-2, "print");
+
+  assert_eq!(state.compile("print(secret) print(type)", "=sandboxed"), Ok(()));
+  assert!(state.set_chunk_env(-1, -2));
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::Ok);
+  assert_eq!(*printed.lock().unwrap(), vec!["nil".to_owned(), "nil".to_owned()]);
+
+  // `print` is a native function without upvalues
+  state.get_global("print");
+  assert!(!state.set_chunk_env(-1, -2));
+  assert_eq!(state.get_top(), 2);
+}
+
+fn open_greeter(state: &mut State) -> c_int {
+  state.new_table();
+  state.push_fn(lua_func!(greet));
+  state.set_field(-2, "greet");
+  1
+}
+
+fn greet(state: &mut State) -> c_int {
+  let greeting = format!("hello, {}", state.check_string(1));
+  state.push_string(&greeting);
+  1
+}
+
+#[test]
+fn test_preload_module() {
+  let mut state = State::new();
+  state.preload_module("greeter", lua_func!(open_greeter));
+  state.open_libs();
+  let status = state.do_string("
+    assert(package.loaded.greeter == nil)
+    local greeter = require('greeter')
+    assert(greeter.greet('lua') == 'hello, lua')
+    assert(require('greeter') == greeter)
+  ");
+  assert_eq!(status, ThreadStatus::Ok);
+  assert_eq!(state.get_top(), 0);
+}
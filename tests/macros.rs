@@ -0,0 +1,52 @@
+#[macro_use]
+extern crate lua;
+
+use lua::{State, ThreadStatus};
+use lua::libc::c_int;
+
+#[test]
+fn test_lua_format() {
+  let mut state = State::new();
+  let (a, b) = (12, 3.5);
+  lua_format!(state, "{}-{}-{}", a, b, "x");
+  assert_eq!(state.to_str_in_place(-1), Some("12-3.5-x"));
+}
+
+fn fail_with_count(state: &mut State) -> c_int {
+  let n = state.get_top();
+  lua_error!(state, "got {} arguments", n)
+}
+
+fn fail_plain(state: &mut State) -> c_int {
+  state.raise_error("plain failure")
+}
+
+#[test]
+fn test_lua_error() {
+  let mut state = State::new();
+  state.open_libs();
+  state.register("fail_with_count", lua_func!(fail_with_count));
+  state.register("fail_plain", lua_func!(fail_plain));
+
+  let status = state.do_string("
+    local ok, err = pcall(fail_with_count, 1, 2, 3)
+    assert(not ok and err == 'got 3 arguments', err)
+    ok, err = pcall(fail_plain)
+    assert(not ok and err == 'plain failure', err)
+  ");
+  assert_eq!(status, ThreadStatus::Ok);
+}
+
+#[test]
+fn test_set_field_static() {
+  let mut state = State::new();
+  let key = lua_cstr!("name");
+  assert_eq!(key.to_bytes(), b"name");
+
+  state.new_table();
+  state.push_string("value");
+  state.set_field_static(-2, lua_cstr!("name"));
+  assert_eq!(state.get_top(), 1);
+  state.get_field(-1, "name");
+  assert_eq!(state.to_str_in_place(-1), Some("value"));
+}
@@ -0,0 +1,25 @@
+extern crate lua;
+
+use lua::{State, PackValue};
+
+#[test]
+fn test_pack_unpack() {
+  let mut state = State::new();
+
+  let bytes = state.pack("<i4", &[PackValue::Integer(42)]).unwrap();
+  assert_eq!(bytes, vec![42, 0, 0, 0]);
+  assert_eq!(state.unpack("<i4", &bytes), Ok(vec![PackValue::Integer(42)]));
+  assert_eq!(state.get_top(), 0);
+
+  let values = vec![PackValue::Number(1.5), PackValue::Bytes(b"a\0b".to_vec())];
+  let bytes = state.pack(">ds1", &values).unwrap();
+  assert_eq!(state.unpack(">ds1", &bytes), Ok(values));
+}
+
+#[test]
+fn test_pack_errors() {
+  let mut state = State::new();
+  assert!(state.pack("<i4", &[]).is_err());
+  assert!(state.unpack("<i4", &[1, 2]).is_err());
+  assert_eq!(state.get_top(), 0);
+}
@@ -0,0 +1,31 @@
+extern crate lua;
+
+use std::env;
+use std::process::Command;
+
+use lua::State;
+
+const CHILD_VAR: &'static str = "LUA_TEST_PANIC_CHILD";
+
+#[test]
+fn test_rust_panic_handler_message() {
+  if env::var_os(CHILD_VAR).is_some() {
+    let mut state = State::new();
+    state.set_rust_panic_handler();
+    state.push_string("something went wrong");
+    state.error();
+  }
+
+  // the panic aborts the process, so observe it from a child process
+  let output = Command::new(env::current_exe().unwrap())
+    .arg("test_rust_panic_handler_message")
+    .arg("--exact")
+    .arg("--nocapture")
+    .env(CHILD_VAR, "1")
+    .output()
+    .unwrap();
+  assert!(!output.status.success());
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert!(stderr.contains("unprotected error in call to Lua API (something went wrong)"),
+    "unexpected stderr: {}", stderr);
+}
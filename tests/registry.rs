@@ -0,0 +1,42 @@
+extern crate lua;
+
+use lua::{State, RegistryKey};
+
+#[test]
+fn test_registry_key() {
+  let mut state = State::new();
+  state.new_table();
+  state.push_value(-1);
+  let key = RegistryKey::new(&mut state);
+  assert_eq!(state.get_top(), 1);
+
+  key.push(&mut state);
+  assert!(state.raw_equal(1, 2));
+  state.pop(1);
+
+  state.push_nil();
+  key.set_from_stack(&mut state);
+  key.push(&mut state);
+  assert!(state.is_nil(-1));
+  state.pop(1);
+
+  state.push_integer(5);
+  key.set_from_stack(&mut state);
+  key.push(&mut state);
+  assert_eq!(state.to_integer(-1), 5);
+  state.pop(1);
+
+  key.unref(&mut state);
+  assert_eq!(state.get_top(), 1);
+}
+
+#[test]
+fn test_registry_key_nil() {
+  let mut state = State::new();
+  state.push_nil();
+  let key = RegistryKey::new(&mut state);
+  assert!(!key.reference().is_nil_ref());
+  key.push(&mut state);
+  assert!(state.is_nil(-1));
+  key.unref(&mut state);
+}
@@ -0,0 +1,57 @@
+extern crate lua;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lua::{State, ThreadStatus};
+
+fn scoped(state: &mut State, cleanups: Arc<AtomicUsize>, fail: bool) -> Result<lua::Integer, ThreadStatus> {
+  state.with_scoped_value(
+    |state| state.push_integer(20),
+    |state| {
+      if fail {
+        state.raise_error("body failed");
+      }
+      state.to_integer(1) + 1
+    },
+    |state| {
+      assert_eq!(state.to_integer(-1), 20);
+      cleanups.fetch_add(1, Ordering::SeqCst);
+    })
+}
+
+#[test]
+fn test_scoped_value_success() {
+  let mut state = State::new();
+  let cleanups = Arc::new(AtomicUsize::new(0));
+  assert_eq!(scoped(&mut state, cleanups.clone(), false), Ok(21));
+  assert_eq!(cleanups.load(Ordering::SeqCst), 1);
+  assert_eq!(state.get_top(), 0);
+}
+
+#[test]
+fn test_scoped_value_error() {
+  let mut state = State::new();
+  let cleanups = Arc::new(AtomicUsize::new(0));
+  let counter = cleanups.clone();
+  state.push_closure_boxed(move |state| {
+    if scoped(state, counter.clone(), true).is_err() {
+      state.error();
+    }
+    0
+  });
+
+  assert_eq!(state.pcall(0, 0, 0), ThreadStatus::RuntimeError);
+  assert_eq!(state.to_str_in_place(-1), Some("body failed"));
+  assert_eq!(cleanups.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_scoped_value_error_unprotected() {
+  let mut state = State::new();
+  let cleanups = Arc::new(AtomicUsize::new(0));
+  assert_eq!(scoped(&mut state, cleanups.clone(), true), Err(ThreadStatus::RuntimeError));
+  assert_eq!(cleanups.load(Ordering::SeqCst), 1);
+  assert_eq!(state.get_top(), 1);
+  assert_eq!(state.to_str_in_place(-1), Some("body failed"));
+}
@@ -0,0 +1,102 @@
+extern crate lua;
+
+use lua::{State, StackError, Type, REGISTRYINDEX};
+
+#[test]
+fn test_stack_guard_restores_top() {
+  let mut state = State::new();
+  state.push_integer(1);
+  {
+    let mut guard = state.stack_guard();
+    guard.push_integer(2);
+    guard.push_string("three");
+    guard.new_table();
+    assert_eq!(guard.get_top(), 4);
+  }
+  assert_eq!(state.get_top(), 1);
+  assert_eq!(state.to_integer(-1), 1);
+}
+
+#[test]
+fn test_stack_guard_commit() {
+  let mut state = State::new();
+  {
+    let mut guard = state.stack_guard();
+    guard.push_integer(1);
+    guard.push_integer(2);
+    guard.commit();
+  }
+  assert_eq!(state.get_top(), 2);
+}
+
+#[test]
+fn test_rotate_checked() {
+  let mut state = State::new();
+  for i in 1..5 {
+    state.push_integer(i);
+  }
+
+  assert_eq!(state.rotate_checked(2, 1), Ok(()));
+  let values: Vec<_> = (1..5).map(|i| state.to_integer(i)).collect();
+  assert_eq!(values, vec![1, 4, 2, 3]);
+
+  assert_eq!(state.rotate_checked(-2, -2), Ok(()));
+  assert_eq!(state.rotate_checked(1, 4), Ok(()));
+  let values: Vec<_> = (1..5).map(|i| state.to_integer(i)).collect();
+  assert_eq!(values, vec![1, 4, 2, 3]);
+
+  assert_eq!(state.rotate_checked(0, 1), Err(StackError::InvalidIndex { index: 0, top: 4 }));
+  assert_eq!(state.rotate_checked(5, 1), Err(StackError::InvalidIndex { index: 5, top: 4 }));
+  assert_eq!(state.rotate_checked(-5, 1), Err(StackError::InvalidIndex { index: -5, top: 4 }));
+  assert!(state.rotate_checked(REGISTRYINDEX, 1).is_err());
+  assert_eq!(state.rotate_checked(3, 3), Err(StackError::CountOutOfRange { count: 3, available: 2 }));
+  assert_eq!(state.rotate_checked(-1, -2), Err(StackError::CountOutOfRange { count: -2, available: 1 }));
+  assert_eq!(state.get_top(), 4);
+}
+
+#[test]
+fn test_ensure_stack() {
+  let mut state = State::new();
+  assert_eq!(state.ensure_stack(100), Ok(()));
+  assert_eq!(state.ensure_stack(1000000000), Err(StackError::Overflow { requested: 1000000000 }));
+  state.assert_stack(100);
+  for i in 0..100 {
+    state.push_integer(i);
+  }
+  assert_eq!(state.get_top(), 100);
+}
+
+#[test]
+#[should_panic(expected = "cannot grow the Lua stack")]
+#[cfg(debug_assertions)]
+fn test_assert_stack_overflow() {
+  let mut state = State::new();
+  state.assert_stack(1000000000);
+}
+
+#[test]
+fn test_dump_stack() {
+  let mut state = State::new();
+  assert!(state.dump_stack().is_empty());
+
+  state.push_integer(42);
+  state.push_string("text");
+  state.push_nil();
+  state.push_bool(true);
+  state.push_number(1.5);
+  state.new_table();
+
+  let snapshot = state.dump_stack();
+  assert_eq!(state.get_top(), 6);
+  assert_eq!(&snapshot[..5], &[
+    (1, Type::Number, "42".to_owned()),
+    (2, Type::String, "text".to_owned()),
+    (3, Type::Nil, "nil".to_owned()),
+    (4, Type::Boolean, "true".to_owned()),
+    (5, Type::Number, "1.5".to_owned()),
+  ]);
+  assert_eq!(snapshot[5].0, 6);
+  assert_eq!(snapshot[5].1, Type::Table);
+  assert!(snapshot[5].2.starts_with("table: "));
+  assert!(state.is_integer(1));
+}
@@ -0,0 +1,185 @@
+extern crate lua;
+extern crate libc;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use lua::{State, Type, Library, ThreadStatus};
+use lua::ffi::{self, lua_State};
+use libc::c_int;
+
+#[test]
+fn test_get_global_opt() {
+  let mut state = State::new();
+  state.push_integer(5);
+  state.set_global("present");
+
+  assert_eq!(state.get_global_opt("present"), Some(Type::Number));
+  assert_eq!(state.to_integer(-1), 5);
+  state.pop(1);
+
+  assert_eq!(state.get_global_opt("absent"), None);
+  assert_eq!(state.get_top(), 0);
+}
+
+#[test]
+fn test_get_field_opt() {
+  let mut state = State::new();
+  state.new_table();
+  state.push_string("value");
+  state.set_field(-2, "present");
+
+  assert_eq!(state.get_field_opt(-1, "present"), Some(Type::String));
+  assert_eq!(state.to_str_in_place(-1), Some("value"));
+  state.pop(1);
+
+  assert_eq!(state.get_field_opt(-1, "absent"), None);
+  assert_eq!(state.get_top(), 1);
+}
+
+#[test]
+fn test_open_single_library() {
+  let mut state = State::new();
+  state.open_math();
+
+  state.get_global("math");
+  assert_eq!(state.get_field(-1, "sqrt"), Type::Function);
+  state.pop(2);
+  assert_eq!(state.get_global_opt("os"), None);
+  assert_eq!(state.get_global_opt("print"), None);
+}
+
+#[test]
+fn test_load_libraries() {
+  let mut state = State::new();
+  state.load_libraries(&[Library::Base, Library::String, Library::Math]);
+
+  let status = state.do_string("
+    assert(math.sqrt(16) == 4)
+    assert(string.rep('a', 3) == 'aaa')
+    assert(os == nil and io == nil and debug == nil)
+  ");
+  assert_eq!(status, ThreadStatus::Ok);
+}
+
+#[test]
+fn test_open_safe_libs() {
+  let mut state = State::new();
+  state.open_safe_libs();
+
+  let status = state.do_string("
+    assert(string.format('%d-%s', 1, 'a') == '1-a')
+    assert(os == nil and io == nil and debug == nil and package == nil)
+    assert(dofile == nil and loadfile == nil and load == nil)
+    assert(collectgarbage == nil)
+  ");
+  assert_eq!(status, ThreadStatus::Ok);
+}
+
+static CLOSED: AtomicBool = AtomicBool::new(false);
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn mark_closed(_: *mut lua_State) -> c_int {
+  CLOSED.store(true, Ordering::SeqCst);
+  0
+}
+
+#[test]
+fn test_from_ptr_owned() {
+  let mut state = unsafe { State::from_ptr_owned(ffi::luaL_newstate()) };
+  state.new_table();
+  state.new_table();
+  state.push_fn(Some(mark_closed));
+  state.set_field(-2, "__gc");
+  state.set_metatable(-2);
+  state.set_global("sentinel");
+
+  assert_eq!(state.do_string("x = 1 + 1"), ThreadStatus::Ok);
+  assert!(!CLOSED.load(Ordering::SeqCst));
+  drop(state);
+  assert!(CLOSED.load(Ordering::SeqCst));
+}
+
+#[test]
+fn test_send_to_thread() {
+  let mut state = State::new();
+  state.open_libs();
+  state.push_integer(20);
+  state.set_global("base");
+
+  let handle = thread::spawn(move || {
+    assert_eq!(state.do_string("result = base + 22"), ThreadStatus::Ok);
+    state
+  });
+  let mut state = handle.join().unwrap();
+  state.get_global("result");
+  assert_eq!(state.to_integer(-1), 42);
+}
+
+#[test]
+fn test_new_native() {
+  let mut state = State::new_native();
+  state.open_libs();
+  assert_eq!(state.do_string("x = ('a'):rep(1000)"), ThreadStatus::Ok);
+  state.get_global("x");
+  assert_eq!(state.raw_len(-1), 1000);
+}
+
+#[test]
+#[should_panic(expected = "not supported by states created with new_native")]
+fn test_new_native_extra() {
+  let mut state = State::new_native();
+  state.set_extra(None);
+}
+
+#[test]
+fn test_thread_status_into_result() {
+  let errors = [
+    ThreadStatus::RuntimeError, ThreadStatus::SyntaxError, ThreadStatus::MemoryError,
+    ThreadStatus::GcError, ThreadStatus::MessageHandlerError, ThreadStatus::FileError,
+  ];
+  assert_eq!(ThreadStatus::Ok.into_result(), Ok(()));
+  assert_eq!(ThreadStatus::Yield.into_result(), Ok(()));
+  for &status in errors.iter() {
+    assert_eq!(status.into_result(), Err(status));
+    assert_eq!(Result::from(status), Err(status));
+  }
+
+  let mut state = State::new();
+  assert_eq!(state.load_string("error('x')").into_result(), Ok(()));
+  assert_eq!(state.pcall(0, 0, 0).into_result(), Err(ThreadStatus::RuntimeError));
+}
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn triple(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  let n = state.check_integer(1);
+  state.push_integer(n * 3);
+  1
+}
+
+#[test]
+fn test_set_global_helpers() {
+  let mut state = State::new();
+  state.open_base();
+  state.set_global_value("limit", 14 as lua::Integer);
+  state.set_global_value("greeting", "hello");
+  state.set_global_fn("triple", Some(triple));
+  assert_eq!(state.get_top(), 0);
+
+  assert_eq!(state.do_string("assert(triple(limit) == 42 and greeting == 'hello')"), ThreadStatus::Ok);
+}
+
+#[test]
+fn test_global() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("width = 640\ntitle = 'window'"), ThreadStatus::Ok);
+
+  assert_eq!(state.global::<lua::Integer>("width"), Some(640));
+  assert_eq!(state.global::<String>("title"), Some("window".to_owned()));
+  assert_eq!(state.global::<bool>("title"), None);
+  assert_eq!(state.global::<lua::Integer>("missing"), None);
+  assert_eq!(state.global::<String>("missing"), None);
+  assert_eq!(state.global::<Option<lua::Integer>>("missing"), None);
+  assert_eq!(state.get_top(), 0);
+}
@@ -0,0 +1,172 @@
+#[macro_use]
+extern crate lua;
+
+use lua::{State, Type, Integer, ThreadStatus};
+use lua::libc::c_int;
+
+#[test]
+fn test_to_str_lossy() {
+  let mut state = State::new();
+  state.push_bytes(b"ab\xffcd");
+  assert_eq!(state.to_str_in_place(-1), None);
+  assert_eq!(state.to_str_lossy(-1).unwrap(), "ab\u{fffd}cd");
+
+  state.push_string("valid");
+  assert_eq!(state.to_str_lossy(-1).unwrap(), "valid");
+
+  state.new_table();
+  assert_eq!(state.to_str_lossy(-1), None);
+}
+
+fn byte_len(state: &mut State) -> c_int {
+  let len = state.check_bytes(1).len();
+  let lossy = state.check_string_lossy(1).into_owned();
+  state.push_integer(len as Integer);
+  state.push_string(&lossy);
+  2
+}
+
+#[test]
+fn test_check_bytes() {
+  let mut state = State::new();
+  state.push_fn(lua_func!(byte_len));
+  state.push_bytes(b"\xfe\x00\xff");
+  assert_eq!(state.pcall(1, 2, 0), ThreadStatus::Ok);
+  assert_eq!(state.to_integer(-2), 3);
+  assert_eq!(state.to_str_in_place(-1), Some("\u{fffd}\u{0}\u{fffd}"));
+}
+
+#[test]
+fn test_to_string_meta() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.do_string("
+    obj = setmetatable({}, { __tostring = function() return 'custom' end })
+  "), ThreadStatus::Ok);
+
+  state.get_global("obj");
+  assert_eq!(state.to_string_meta(-1), "custom");
+  assert_eq!(state.get_top(), 1);
+  assert!(state.is_table(-1));
+
+  state.push_integer(12);
+  assert_eq!(state.to_string_meta(-1), "12");
+  assert!(state.is_integer(-1));
+  state.push_nil();
+  assert_eq!(state.to_string_meta(-1), "nil");
+  assert_eq!(state.get_top(), 3);
+}
+
+#[test]
+fn test_push_interned() {
+  let mut state = State::new();
+  let name = state.intern("name");
+  let long = state.intern("a string that is too long for Lua to intern by itself");
+  let empty = state.intern("");
+  assert_eq!(state.get_top(), 0);
+
+  state.push_interned(name);
+  state.push_interned(name);
+  state.push_interned(long);
+  state.push_interned(empty);
+  assert_eq!(state.get_top(), 4);
+  assert_eq!(state.to_str_in_place(1), Some("name"));
+  assert_eq!(state.to_str_in_place(2), Some("name"));
+  assert_eq!(state.to_str_in_place(3), Some("a string that is too long for Lua to intern by itself"));
+  assert_eq!(state.to_str_in_place(4), Some(""));
+  assert!(state.raw_equal(1, 2));
+
+  state.unreference(lua::REGISTRYINDEX, long);
+  state.push_interned(name);
+  assert_eq!(state.to_str_in_place(-1), Some("name"));
+}
+
+#[test]
+fn test_interior_nul() {
+  let mut state = State::new();
+  state.push_string("a\0b");
+  assert_eq!(state.raw_len(-1), 3);
+  assert_eq!(state.to_bytes_in_place(-1), Some(&b"a\0b"[..]));
+
+  state.new_table();
+  state.push_integer(1);
+  state.set_field(-2, "k\0ey");
+  assert_eq!(state.get_field(-1, "k\0ey"), Type::Number);
+  assert_eq!(state.to_integer(-1), 1);
+  assert_eq!(state.get_field(-2, "k"), Type::Nil);
+  state.pop(3);
+
+  state.push_integer(2);
+  state.set_global("g\0lobal");
+  assert_eq!(state.get_global("g\0lobal"), Type::Number);
+  assert_eq!(state.to_integer(-1), 2);
+  assert_eq!(state.get_global("g"), Type::Nil);
+  assert_eq!(state.get_top(), 3);
+}
+
+#[test]
+fn test_concat_to_string() {
+  let mut state = State::new();
+  state.push_string("answer: ");
+  state.push_integer(42);
+  state.push_string("!");
+  assert_eq!(state.concat_to_string(3), "answer: 42!");
+  assert_eq!(state.get_top(), 0);
+
+  assert_eq!(state.concat_to_string(0), "");
+  assert_eq!(state.get_top(), 0);
+}
+
+fn is_hello(state: &mut State) -> c_int {
+  let matches = state.check_str(1) == "hello";
+  state.push_bool(matches);
+  1
+}
+
+#[test]
+fn test_check_str() {
+  let mut state = State::new();
+  state.open_libs();
+  state.push_fn(lua_func!(is_hello));
+  state.set_global("is_hello");
+  let status = state.do_string(r#"
+    assert(is_hello("hello"))
+    assert(not is_hello("world"))
+    assert(not is_hello(42))
+    local ok, err = pcall(is_hello, "\xff")
+    assert(not ok and err:find("invalid UTF%-8"), err)
+    ok, err = pcall(is_hello, {})
+    assert(not ok and err:find("string expected"), err)
+  "#);
+  assert_eq!(status, ThreadStatus::Ok);
+}
+
+fn optional_args(state: &mut State) -> c_int {
+  let bytes = state.opt_bytes(1, b"\x00\xff");
+  let lossy = state.opt_string_lossy(1, "default").into_owned();
+  state.push_bytes(&bytes);
+  state.push_string(&lossy);
+  2
+}
+
+#[test]
+fn test_opt_bytes() {
+  let mut state = State::new();
+  state.push_fn(lua_func!(optional_args));
+  state.push_bytes(b"a\xfeb");
+  assert_eq!(state.pcall(1, 2, 0), ThreadStatus::Ok);
+  assert_eq!(state.to_bytes_in_place(-2), Some(&b"a\xfeb"[..]));
+  assert_eq!(state.to_str_in_place(-1), Some("a\u{fffd}b"));
+  state.pop(2);
+
+  state.push_fn(lua_func!(optional_args));
+  state.push_nil();
+  assert_eq!(state.pcall(1, 2, 0), ThreadStatus::Ok);
+  assert_eq!(state.to_bytes_in_place(-2), Some(&b"\x00\xff"[..]));
+  assert_eq!(state.to_str_in_place(-1), Some("default"));
+  state.pop(2);
+
+  state.push_fn(lua_func!(optional_args));
+  state.new_table();
+  assert!(state.pcall(1, 2, 0).is_err());
+}
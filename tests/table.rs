@@ -0,0 +1,287 @@
+extern crate lua;
+
+use std::collections::BTreeMap;
+
+use lua::{State, Type, Integer, ThreadStatus};
+
+#[test]
+fn test_get_or_create_table() {
+  let mut state = State::new();
+  state.open_libs();
+  state.push_global_table();
+
+  assert!(state.get_or_create_table(-1, "mymod"));
+  assert!(state.get_or_create_table(-1, "sub"));
+  let first = state.to_pointer(-1);
+  state.pop(2);
+
+  assert!(!state.get_or_create_table(-1, "mymod"));
+  assert!(!state.get_or_create_table(-1, "sub"));
+  assert_eq!(state.to_pointer(-1), first);
+  state.pop(3);
+
+  assert_eq!(state.do_string("assert(type(mymod.sub) == 'table')"), ThreadStatus::Ok);
+}
+
+#[test]
+fn test_for_each_pair() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("t = {1, 2, 3, x = 4, y = 5}"), ThreadStatus::Ok);
+  state.get_global("t");
+  state.push_integer(0);
+
+  let mut sum = 0;
+  state.for_each_pair(-2, |state| {
+    sum += state.to_integer(-1);
+  });
+  assert_eq!(sum, 15);
+  assert_eq!(state.get_top(), 2);
+}
+
+#[test]
+fn test_raw_field_access() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.do_string("
+    assigned = {}
+    t = setmetatable({}, {
+      __index = function() return 'sentinel' end,
+      __newindex = function(t, k, v) assigned[k] = v end,
+    })
+  "), ThreadStatus::Ok);
+  state.get_global("t");
+
+  assert_eq!(state.get_field(-1, "x"), Type::String);
+  assert_eq!(state.to_str_in_place(-1), Some("sentinel"));
+  state.pop(1);
+  assert_eq!(state.get_field_raw(-1, "x"), Type::Nil);
+  state.pop(1);
+  assert_eq!(state.geti(-1, 1), Type::String);
+  state.pop(1);
+  assert_eq!(state.raw_geti(-1, 1), Type::Nil);
+  state.pop(1);
+
+  state.push_integer(1);
+  state.set_field(-2, "a");
+  state.push_integer(2);
+  state.set_field_raw(-2, "b");
+  state.push_integer(3);
+  state.seti(-2, 1);
+  state.push_integer(4);
+  state.raw_seti(-2, 2);
+  assert_eq!(state.get_top(), 1);
+
+  assert_eq!(state.do_string("
+    assert(assigned.a == 1 and rawget(t, 'a') == nil)
+    assert(assigned.b == nil and rawget(t, 'b') == 2)
+    assert(assigned[1] == 3 and rawget(t, 1) == nil)
+    assert(assigned[2] == nil and rawget(t, 2) == 4)
+  "), ThreadStatus::Ok);
+}
+
+#[test]
+fn test_set_get_path() {
+  let mut state = State::new();
+  state.open_base();
+  state.push_integer(800);
+  state.set_path("app.window.width");
+  state.push_string("demo");
+  state.set_path("title");
+  assert_eq!(state.get_top(), 0);
+
+  assert_eq!(state.get_path("app.window.width"), Type::Number);
+  assert_eq!(state.to_integer(-1), 800);
+  assert_eq!(state.get_path("title"), Type::String);
+  assert_eq!(state.to_str_in_place(-1), Some("demo"));
+  state.pop(2);
+
+  assert_eq!(state.do_string("assert(app.window.width == 800)"), ThreadStatus::Ok);
+  assert_eq!(state.get_path("app.missing.width"), Type::Nil);
+  assert_eq!(state.get_path("app.window.width.x"), Type::Nil);
+  assert_eq!(state.get_top(), 2);
+}
+
+#[test]
+fn test_length_metamethod() {
+  let mut state = State::new();
+  state.open_base();
+  assert_eq!(state.do_string("t = setmetatable({1, 2}, {__len = function() return 10 end})"), ThreadStatus::Ok);
+  state.get_global("t");
+
+  assert_eq!(state.length(-1), 10);
+  assert_eq!(state.raw_len(-1), 2);
+  assert_eq!(state.get_top(), 1);
+
+  state.push_string("abc");
+  assert_eq!(state.length(-1), 3);
+  assert_eq!(state.raw_len(-1), 3);
+}
+
+#[test]
+fn test_field_bytes() {
+  let mut state = State::new();
+  state.new_table();
+  state.push_integer(3);
+  state.set_field_bytes(-2, b"\xffkey\0");
+  assert_eq!(state.get_top(), 1);
+
+  assert_eq!(state.get_field_bytes(-1, b"\xffkey\0"), Type::Number);
+  assert_eq!(state.to_integer(-1), 3);
+  assert_eq!(state.get_field_bytes(-2, b"\xffkey"), Type::Nil);
+  assert_eq!(state.get_top(), 3);
+}
+
+#[test]
+fn test_for_each_index() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("t = {10, 20, 30, nil, 50, key = 60}"), ThreadStatus::Ok);
+  state.get_global("t");
+
+  let mut sum = 0;
+  let mut last = 0;
+  state.for_each_index(-1, |state, i| {
+    sum += state.to_integer(-1);
+    last = i;
+  });
+  assert_eq!(sum, 60);
+  assert_eq!(last, 3);
+  assert_eq!(state.get_top(), 1);
+}
+
+#[test]
+fn test_collect_pairs() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("t = {one = 1, two = 2, three = 'x', [4] = 4}"), ThreadStatus::Ok);
+  state.get_global("t");
+
+  let map: BTreeMap<String, Integer> = state.collect_pairs(-1);
+  let expected: BTreeMap<String, Integer> =
+    vec![("one".to_owned(), 1), ("two".to_owned(), 2), ("4".to_owned(), 4)].into_iter().collect();
+  assert_eq!(map, expected);
+  assert_eq!(state.get_top(), 1);
+}
+
+#[test]
+fn test_collect_pairs_mixed_keys() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("t = {10, 20, ['1'] = 99}"), ThreadStatus::Ok);
+  state.get_global("t");
+
+  // integer keys are coerced to strings and collide with the string key
+  let strings: BTreeMap<String, Integer> = state.collect_pairs(-1);
+  assert_eq!(strings.len(), 2);
+  assert_eq!(strings.get("2"), Some(&20));
+  let one = strings.get("1").cloned();
+  assert!(one == Some(10) || one == Some(99));
+
+  // the string key is not an integer, so it is skipped
+  let integers: BTreeMap<Integer, Integer> = state.collect_pairs(-1);
+  let expected: BTreeMap<Integer, Integer> = vec![(1, 10), (2, 20)].into_iter().collect();
+  assert_eq!(integers, expected);
+  assert_eq!(state.get_top(), 1);
+}
+
+#[test]
+fn test_get_set_array() {
+  let mut state = State::new();
+  assert_eq!(state.load_string("return {'first', 'second'}"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+
+  assert_eq!(state.get_array(-1, 0), Type::String);
+  assert_eq!(state.to_str_in_place(-1), Some("first"));
+  state.pop(1);
+  assert_eq!(state.get_array(-1, 1), Type::String);
+  assert_eq!(state.to_str_in_place(-1), Some("second"));
+  state.pop(1);
+  assert_eq!(state.get_array(-1, 2), Type::Nil);
+  state.pop(1);
+
+  state.push_string("third");
+  state.set_array(-2, 2);
+  assert_eq!(state.raw_geti(-1, 3), Type::String);
+  assert_eq!(state.to_str_in_place(-1), Some("third"));
+  state.pop(1);
+  assert_eq!(state.raw_len(-1), 3);
+}
+
+#[test]
+fn test_field() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("config = {width = 800, title = 'main', scale = 1.5}"), ThreadStatus::Ok);
+  state.get_global("config");
+
+  assert_eq!(state.field::<i64>(-1, "width"), Some(800));
+  assert_eq!(state.field::<String>(-1, "title"), Some("main".to_owned()));
+  assert_eq!(state.field::<Integer>(-1, "scale"), None);
+  assert_eq!(state.field::<Integer>(-1, "height"), None);
+  assert_eq!(state.field::<Option<Integer>>(-1, "height"), None);
+  assert_eq!(state.get_top(), 1);
+}
+
+#[test]
+fn test_set_field_value() {
+  let mut state = State::new();
+  state.open_libs();
+  assert_eq!(state.do_string("config = {}"), ThreadStatus::Ok);
+  state.get_global("config");
+  state.set_field_value(-1, "width", 800 as Integer);
+  state.set_field_value(-1, "title", "main");
+  assert_eq!(state.get_top(), 1);
+
+  assert_eq!(state.do_string("
+    assert(config.width == 800)
+    assert(config.title == 'main')
+  "), ThreadStatus::Ok);
+}
+
+#[test]
+fn test_set_helpers_relative_index() {
+  let mut state = State::new();
+  state.new_table();
+  state.new_table();
+
+  // the target is the table on the top, not the one below it
+  state.set_field_value(-1, "value", 1 as Integer);
+  state.push_integer(2);
+  state.set_field_raw(-2, "raw");
+  state.push_integer(3);
+  state.set_field_bytes(-2, b"by\0tes");
+  state.push_integer(4);
+  state.set_field(-2, "nul\0key");
+  assert_eq!(state.get_top(), 2);
+
+  assert_eq!(state.field::<Integer>(-1, "value"), Some(1));
+  assert_eq!(state.field::<Integer>(-1, "raw"), Some(2));
+  assert_eq!(state.get_field_bytes(-1, b"by\0tes"), Type::Number);
+  assert_eq!(state.get_field(-2, "nul\0key"), Type::Number);
+  state.pop(2);
+  assert!(state.keys(1).is_empty());
+
+  assert_eq!(state.load_string("return x"), ThreadStatus::Ok);
+  state.new_table();
+  state.set_field_value(-1, "x", "from env");
+  assert!(state.set_chunk_env(-2, -1));
+  state.pop(1);
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+  assert_eq!(state.to_str_in_place(-1), Some("from env"));
+}
+
+#[test]
+fn test_push_map() {
+  let mut state = State::new();
+  state.open_libs();
+  state.push_string("below");
+  state.push_map(vec![("width", 800 as Integer), ("height", 600 as Integer), ("width", 1024 as Integer)]);
+  assert_eq!(state.get_top(), 2);
+  assert_eq!(state.to_str_in_place(1), Some("below"));
+  state.set_global("size");
+  assert_eq!(state.do_string("
+    assert(size.width == 1024 and size.height == 600)
+    local n = 0
+    for _ in pairs(size) do n = n + 1 end
+    assert(n == 2)
+  "), ThreadStatus::Ok);
+
+  state.push_map(vec![(None, 1 as Integer), (Some(0.0 / 0.0), 2), (Some(1.5), 3)]);
+  assert_eq!(state.keys(-1), vec![lua::LuaValue::Number(1.5)]);
+}
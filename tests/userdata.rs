@@ -0,0 +1,223 @@
+extern crate lua;
+extern crate libc;
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use lua::ffi::lua_State;
+use lua::State;
+use libc::c_int;
+
+static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+struct Point {
+  x: i64,
+  y: i64,
+}
+
+impl Drop for Point {
+  fn drop(&mut self) {
+    DROPS.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn point_gc(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  let p = state.check_userdata(1, "Point") as *mut Point;
+  std::ptr::drop_in_place(p);
+  0
+}
+
+#[test]
+fn test_push_userdata() {
+  let mut state = State::new();
+  state.new_metatable("Point");
+  state.push_fn(Some(point_gc));
+  state.set_field(-2, "__gc");
+  state.pop(1);
+
+  state.push_userdata(Point { x: 12, y: 34 }, "Point");
+  {
+    let p: &mut Point = unsafe { state.check_userdata_typed(-1, "Point") };
+    assert_eq!((p.x, p.y), (12, 34));
+  }
+  assert_eq!(DROPS.load(Ordering::SeqCst), 0);
+
+  state.pop(1);
+  state.gc(lua::GcOption::Collect, 0);
+  assert_eq!(DROPS.load(Ordering::SeqCst), 1);
+}
+
+struct VecWrapper {
+  data: Vec<i64>,
+}
+
+static VEC_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+impl Drop for VecWrapper {
+  fn drop(&mut self) {
+    VEC_DROPS.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn vec_new(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  state.push_userdata(VecWrapper { data: Vec::new() }, "VecWrapper");
+  1
+}
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn vec_get(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  let v = &*(state.check_userdata(1, "VecWrapper") as *mut VecWrapper);
+  let i = state.check_integer(2) as usize;
+  match v.data.get(i) {
+    Some(value) => state.push_integer(*value),
+    None        => state.push_nil()
+  };
+  1
+}
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn vec_push(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  let v = state.check_userdata(1, "VecWrapper") as *mut VecWrapper;
+  let i = state.check_integer(2);
+  (*v).data.push(i);
+  0
+}
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn vec_len(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  let v = state.check_userdata(1, "VecWrapper") as *mut VecWrapper;
+  state.push_integer((*v).data.len() as i64);
+  1
+}
+
+#[allow(non_snake_case)]
+unsafe extern "C" fn vec_gc(L: *mut lua_State) -> c_int {
+  let mut state = State::from_ptr(L);
+  let v = state.check_userdata(1, "VecWrapper") as *mut VecWrapper;
+  std::ptr::drop_in_place(v);
+  0
+}
+
+#[test]
+fn test_metatable_builder() {
+  let mut state = State::new();
+  state.open_base();
+  let created = state.metatable("VecWrapper")
+    .method("get", Some(vec_get))
+    .method("push", Some(vec_push))
+    .method("len", Some(vec_len))
+    .meta("__gc", Some(vec_gc))
+    .finish();
+  assert!(created);
+  assert_eq!(state.get_top(), 0);
+  state.register("new_vec", Some(vec_new));
+
+  assert_eq!(state.do_string("
+    local v = new_vec()
+    v:push(12)
+    v:push(34)
+    assert(v:len() == 2)
+    assert(v:get(0) == 12 and v:get(1) == 34 and v:get(2) == nil)
+  "), lua::ThreadStatus::Ok);
+  state.gc(lua::GcOption::Collect, 0);
+  assert_eq!(VEC_DROPS.load(Ordering::SeqCst), 1);
+}
+
+struct Flagged(Rc<Cell<bool>>);
+
+impl Drop for Flagged {
+  fn drop(&mut self) {
+    self.0.set(true);
+  }
+}
+
+#[test]
+fn test_register_type() {
+  let mut state = State::new();
+  state.register_type::<Flagged>("Flagged");
+  assert_eq!(state.get_top(), 0);
+
+  let dropped = Rc::new(Cell::new(false));
+  state.push_userdata(Flagged(dropped.clone()), "Flagged");
+  state.pop(1);
+  assert!(!dropped.get());
+
+  state.gc(lua::GcOption::Collect, 0);
+  assert!(dropped.get());
+  assert_eq!(Rc::strong_count(&dropped), 1);
+}
+
+struct Meters(f64);
+struct Seconds(f64);
+
+#[test]
+fn test_get_userdata_checks_type() {
+  let mut state = State::new();
+  state.register_type::<Meters>("Meters");
+  state.register_type::<Seconds>("Seconds");
+
+  state.push_userdata(Meters(3.0), "Meters");
+  state.push_userdata(Seconds(4.0), "Seconds");
+  state.new_userdata(8);
+  state.push_integer(1);
+
+  assert_eq!(state.get_userdata::<Meters>(1).map(|m| m.0), Some(3.0));
+  assert_eq!(state.get_userdata::<Seconds>(2).map(|s| s.0), Some(4.0));
+  assert!(state.get_userdata::<Seconds>(1).is_none());
+  assert!(state.get_userdata::<Meters>(2).is_none());
+  assert!(state.get_userdata::<Meters>(3).is_none());
+  assert!(state.get_userdata::<Meters>(4).is_none());
+  assert_eq!(state.get_top(), 4);
+
+  state.get_userdata::<Meters>(1).unwrap().0 = 5.0;
+  assert_eq!(state.get_userdata::<Meters>(1).map(|m| m.0), Some(5.0));
+}
+
+#[test]
+#[should_panic]
+fn test_push_userdata_rejects_other_registered_type() {
+  let mut state = State::new();
+  state.register_type::<Meters>("Meters");
+  state.push_userdata(Seconds(1.0), "Meters");
+}
+
+#[test]
+#[should_panic]
+fn test_register_type_rejects_retag() {
+  let mut state = State::new();
+  state.register_type::<Meters>("Units");
+  state.register_type::<Seconds>("Units");
+}
+
+#[test]
+fn test_register_type_twice() {
+  let mut state = State::new();
+  state.register_type::<Meters>("Meters");
+  state.register_type::<Meters>("Meters");
+  state.push_userdata(Meters(2.0), "Meters");
+  assert_eq!(state.get_userdata::<Meters>(-1).map(|m| m.0), Some(2.0));
+  assert_eq!(state.get_top(), 1);
+}
+
+#[test]
+fn test_get_userdata_rejects_borrowed_metatable() {
+  let mut state = State::new();
+  state.register_type::<Flagged>("Flagged");
+
+  // A one-byte userdata given the metatable of `Flagged` must not pass as one,
+  // and its `__gc` must leave it alone.
+  unsafe { *state.new_userdata_typed::<u8>() = 7 };
+  state.set_metatable_from_registry("Flagged");
+  assert!(state.get_userdata::<Flagged>(-1).is_none());
+  state.pop(1);
+  state.gc(lua::GcOption::Collect, 0);
+  assert_eq!(state.get_top(), 0);
+}
@@ -0,0 +1,115 @@
+extern crate lua;
+
+use lua::{State, Type, LuaValue, ThreadStatus};
+
+#[test]
+fn test_to_value_primitives() {
+  let mut state = State::new();
+  state.open_base();
+  state.push_nil();
+  state.push_bool(true);
+  state.push_integer(7);
+  state.push_number(2.5);
+  state.push_bytes(b"a\0b");
+  state.get_global("print");
+  state.new_thread();
+
+  assert_eq!(state.to_value(1), LuaValue::Nil);
+  assert_eq!(state.to_value(2), LuaValue::Bool(true));
+  assert_eq!(state.to_value(3), LuaValue::Integer(7));
+  assert_eq!(state.to_value(4), LuaValue::Number(2.5));
+  assert_eq!(state.to_value(5), LuaValue::Str(b"a\0b".to_vec()));
+  assert_eq!(state.to_value(6), LuaValue::Function);
+  assert_eq!(state.to_value(7), LuaValue::Other(Type::Thread));
+  assert_eq!(state.to_value(8), LuaValue::Nil);
+  assert_eq!(state.get_top(), 7);
+}
+
+#[test]
+fn test_to_value_table() {
+  let mut state = State::new();
+  assert_eq!(state.do_string("t = {10, name = 'x', inner = {}}"), ThreadStatus::Ok);
+  state.get_global("t");
+
+  let mut pairs = match state.to_value(-1) {
+    LuaValue::Table(pairs) => pairs,
+    other => panic!("expected a table, got {:?}", other),
+  };
+  pairs.sort_by_key(|pair| format!("{:?}", pair.0));
+  assert_eq!(pairs, vec![
+    (LuaValue::Integer(1), LuaValue::Integer(10)),
+    (LuaValue::Str(b"inner".to_vec()), LuaValue::Other(Type::Table)),
+    (LuaValue::Str(b"name".to_vec()), LuaValue::Str(b"x".to_vec())),
+  ]);
+
+  assert_eq!(state.to_value_depth(-1, 0), LuaValue::Other(Type::Table));
+  assert_eq!(state.get_top(), 1);
+}
+
+#[test]
+fn test_keys() {
+  let mut state = State::new();
+  assert_eq!(state.load_string("return {x = 1, y = 2, [1] = 'a'}"), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+
+  let keys = state.keys(-1);
+  assert_eq!(state.get_top(), 1);
+  assert_eq!(keys.len(), 3);
+  assert!(keys.contains(&LuaValue::Str(b"x".to_vec())));
+  assert!(keys.contains(&LuaValue::Str(b"y".to_vec())));
+  assert!(keys.contains(&LuaValue::Integer(1)));
+  assert!(!keys.contains(&LuaValue::Str(b"a".to_vec())));
+
+  state.new_table();
+  assert!(state.keys(-1).is_empty());
+}
+
+#[test]
+fn test_dump_value() {
+  let mut state = State::new();
+  assert_eq!(state.load_string(r#"
+    return {1, 2.5, "three", nested = {flag = true, [1.5] = "x\n"}, empty = {}, ["not an id"] = 0}
+  "#), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+  assert_eq!(state.dump_value(-1), "\
+{
+  [1] = 1,
+  [2] = 2.5,
+  [3] = \"three\",
+  [\"not an id\"] = 0,
+  empty = {},
+  nested = {
+    [1.5] = \"x\\n\",
+    flag = true,
+  },
+}");
+  assert_eq!(state.get_top(), 1);
+
+  state.push_number(3.0);
+  assert_eq!(state.dump_value(-1), "3.0");
+  state.push_integer(3);
+  assert_eq!(state.dump_value(-1), "3");
+  state.push_nil();
+  assert_eq!(state.dump_value(-1), "nil");
+  assert_eq!(state.dump_value(1000), "nil");
+}
+
+#[test]
+fn test_dump_value_cycle() {
+  let mut state = State::new();
+  assert_eq!(state.load_string("
+    local shared = {}
+    local t = {a = shared, b = shared, child = {}}
+    t.child.parent = t
+    return t
+  "), ThreadStatus::Ok);
+  assert_eq!(state.pcall(0, 1, 0), ThreadStatus::Ok);
+  assert_eq!(state.dump_value(-1), "\
+{
+  a = {},
+  b = {},
+  child = {
+    parent = <cycle>,
+  },
+}");
+}